@@ -0,0 +1,126 @@
+use serde::Serialize;
+use std::fmt;
+use wasm_bindgen::JsValue;
+
+/// Failure reported by the engine. Crosses into JS as a plain object with a
+/// `kind` tag, the variant's fields and a readable `message`, e.g.
+/// `{ kind: "InvalidRotations", rotations: 3, message: "..." }`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum WfcError {
+    /// A JS argument couldn't be deserialized into the expected shape.
+    InvalidArgument { reason: String },
+    /// A flat pixel buffer is empty or its length isn't
+    /// `width * height * channels`.
+    InvalidPixelBuffer { width: usize, height: usize, channels: usize, len: usize },
+    /// The input image has no pixels.
+    EmptyInput,
+    /// Row `row` of the input has `found` pixels where row 0 has `expected`.
+    NonRectangularInput { row: usize, expected: usize, found: usize },
+    /// The tile is zero pixels wide or tall.
+    InvalidTileSize { tile_width: usize, tile_height: usize },
+    TileLargerThanInput { tile_width: usize, tile_height: usize, width: usize, height: usize },
+    /// The output grid is zero cells wide or tall.
+    InvalidOutputSize { width: usize, height: usize },
+    InvalidSymmetry { symmetry: u8 },
+    InvalidRotations { rotations: usize },
+    /// `stride` is zero or leaves no overlap between neighboring tiles.
+    InvalidStride { stride: usize, tile_size: usize },
+    /// `transforms` has bits other than `ROT90 | ROT180 | FLIP_H | FLIP_V`.
+    InvalidTransforms { transforms: u8 },
+    HexWithDiagonal,
+    /// `min_count` filtered out every pattern.
+    NoPatterns { min_count: usize },
+    /// No pattern can sit on some part of the constrained output border.
+    UnsatisfiableBorder,
+    TileOutOfRange { tile_idx: usize, tile_count: usize },
+    /// A constraint contradicts the current grid.
+    Contradiction,
+    /// An output buffer has the wrong length.
+    BufferSizeMismatch { expected: usize, found: usize },
+    /// Encoding the output image failed.
+    Encoding { reason: String },
+    /// A saved state couldn't be decoded.
+    InvalidState { reason: String },
+    /// A saved state came from an engine with another size or tile set.
+    StateMismatch,
+}
+
+impl fmt::Display for WfcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WfcError::InvalidArgument { reason } => write!(f, "Invalid argument: {}", reason),
+            WfcError::InvalidPixelBuffer { width, height, channels, len } => write!(
+                f,
+                "Buffer of {} bytes does not match a {}x{} image with {} channels (need at least 1x1).",
+                len, width, height, channels
+            ),
+            WfcError::EmptyInput => write!(f, "Input image is empty."),
+            WfcError::NonRectangularInput { row, expected, found } => write!(
+                f,
+                "Input row {} has {} pixels, expected {} like the first row.",
+                row, found, expected
+            ),
+            WfcError::InvalidTileSize { tile_width, tile_height } => write!(
+                f,
+                "Tile size must be at least 1x1, got {}x{}.",
+                tile_width, tile_height
+            ),
+            WfcError::TileLargerThanInput { tile_width, tile_height, width, height } => write!(
+                f,
+                "Tile size {}x{} does not fit in the {}x{} input.",
+                tile_width, tile_height, width, height
+            ),
+            WfcError::InvalidOutputSize { width, height } => {
+                write!(f, "Output size must be at least 1x1, got {}x{}.", width, height)
+            }
+            WfcError::InvalidSymmetry { symmetry } => write!(f, "Symmetry must be 0, 1 or 2, got {}.", symmetry),
+            WfcError::InvalidRotations { rotations } => write!(f, "Rotations must be 1, 2 or 4, got {}.", rotations),
+            WfcError::InvalidStride { stride, tile_size } => write!(
+                f,
+                "Stride must be between 1 and tile_size - 1 ({}), got {}.",
+                tile_size.saturating_sub(1),
+                stride
+            ),
+            WfcError::InvalidTransforms { transforms } => {
+                write!(f, "Transforms must be a combination of flags 1, 2, 4 and 8, got {}.", transforms)
+            }
+            WfcError::HexWithDiagonal => write!(f, "Hex grids cannot use diagonal adjacency."),
+            WfcError::NoPatterns { min_count } => {
+                write!(f, "No pattern appears at least {} times in the input.", min_count)
+            }
+            WfcError::UnsatisfiableBorder => write!(f, "No patterns fit the constrained output border."),
+            WfcError::TileOutOfRange { tile_idx, tile_count } => {
+                write!(f, "Tile index {} is out of range for {} tiles.", tile_idx, tile_count)
+            }
+            WfcError::Contradiction => write!(f, "The constraint contradicts the current grid."),
+            WfcError::BufferSizeMismatch { expected, found } => {
+                write!(f, "Buffer has {} bytes, expected {}.", found, expected)
+            }
+            WfcError::Encoding { reason } => write!(f, "Image encoding failed: {}", reason),
+            WfcError::InvalidState { reason } => write!(f, "Invalid saved state: {}", reason),
+            WfcError::StateMismatch => write!(f, "Saved state has different output dimensions or tile set."),
+        }
+    }
+}
+
+impl std::error::Error for WfcError {}
+
+impl From<serde_wasm_bindgen::Error> for WfcError {
+    fn from(e: serde_wasm_bindgen::Error) -> Self {
+        WfcError::InvalidArgument { reason: e.to_string() }
+    }
+}
+
+impl From<WfcError> for JsValue {
+    fn from(e: WfcError) -> Self {
+        let message = e.to_string();
+        match serde_wasm_bindgen::to_value(&e) {
+            Ok(value) => {
+                let _ = js_sys::Reflect::set(&value, &JsValue::from_str("message"), &JsValue::from_str(&message));
+                value
+            }
+            Err(_) => JsValue::from_str(&message),
+        }
+    }
+}
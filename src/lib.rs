@@ -1,352 +1,3556 @@
 use wasm_bindgen::prelude::*;
 use rand::prelude::*;
-use rand::rngs::SmallRng;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use serde::{Serialize, Deserialize};
 
+mod error;
+mod mask;
+mod rng;
+
+pub use error::WfcError;
+use mask::{MaskGrid, TileMask};
+use rng::{hash_unit, EngineRng};
+
 #[wasm_bindgen]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    /// Opacity, `255` when omitted from the JS object. Part of pixel
+    /// equality, so transparent and opaque pixels never overlap.
+    #[serde(default = "opaque")]
+    pub a: u8,
+}
+
+fn opaque() -> u8 {
+    255
 }
 
 type Tile = Vec<Vec<Color>>;
 
+// Which input borders a pattern was seen touching, indexed like `DIRECTIONS`.
+type Edges = [bool; 4];
+
+// Neighbor offsets as `(dr, dc)`, indexed by direction: up, down, left, right.
+// Shared by `compute_adjacencies` and `propagate`; the order decides which
+// contradiction surfaces first, so changing it changes seeded outputs.
+const DIRECTIONS: [(isize, isize); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+// `DIRECTIONS` followed by the four diagonals, for 8-neighbor mode.
+const DIRECTIONS_8: [(isize, isize); 8] = [
+    (-1, 0), (1, 0), (0, -1), (0, 1),
+    (-1, -1), (-1, 1), (1, -1), (1, 1),
+];
+
+// The six hex neighbors in axial coordinates (row = r, col = q): the four
+// of `DIRECTIONS` plus up-right and down-left.
+const HEX_DIRECTIONS: [(isize, isize); 6] = [
+    (-1, 0), (1, 0), (0, -1), (0, 1),
+    (-1, 1), (1, -1),
+];
+
+// Full-grid restarts after which `run_with_budget` gives up.
+const MAX_FULL_RESETS: usize = 8;
+
+/// Flags for `EngineOptions::transforms`. The variants added for each
+/// pattern are every combination of the selected transforms, so e.g.
+/// `ROT90 | FLIP_H` yields all 8 rotations and reflections.
+pub const ROT90: u8 = 1;
+pub const ROT180: u8 = 2;
+/// Mirror left to right.
+pub const FLIP_H: u8 = 4;
+/// Mirror top to bottom.
+pub const FLIP_V: u8 = 8;
+
+// Upper bound on `step()` calls per output cell in `run_to_completion`.
+const MAX_STEPS_PER_CELL: usize = 64;
+
+/// Construction parameters for `WfcEngine::with_options`. Fields missing from
+/// the JS object fall back to the defaults used by `new`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EngineOptions {
+    /// Side length of a square output; `output_width`/`output_height`
+    /// override it per axis for rectangular outputs.
+    pub output_size: usize,
+    pub output_width: Option<usize>,
+    pub output_height: Option<usize>,
+    /// Side length of a square pattern; `tile_width`/`tile_height` override
+    /// it per axis for rectangular patterns such as 3x2. Quarter turns would
+    /// swap the sides of a non-square pattern, so those only use the
+    /// transforms that keep its shape (`ROT180`, `FLIP_H` and `FLIP_V`).
+    pub tile_size: usize,
+    pub tile_width: Option<usize>,
+    pub tile_height: Option<usize>,
+    /// Pixels between neighboring cells, from `1` (the classic overlapping
+    /// model) up to one less than the shorter tile side. Neighbors must agree
+    /// on the remaining pixels of overlap, so a larger stride covers the
+    /// same area with fewer cells and a faster solve, at some loss of detail.
+    pub stride: usize,
+    pub seed: Option<u64>,
+    /// Variants of each pattern added during extraction: `0` for none, `1` for
+    /// the four rotations, `2` for rotations plus reflections (all 8 of D4).
+    pub symmetry: u8,
+    /// Number of evenly spaced rotations (`1`, `2` or `4`) applied when
+    /// `symmetry` is non-zero. `1` disables rotation for directional inputs.
+    pub rotations: usize,
+    /// Exact set of pattern transforms as a bitmask of `ROT90` (1), `ROT180`
+    /// (2), `FLIP_H` (4) and `FLIP_V` (8), e.g. `4` for mirror symmetry
+    /// without rotation. Overrides `symmetry` and `rotations` when set. Each
+    /// added transform can multiply the pattern count, which slows
+    /// propagation; past 64 patterns each cell also grows from one 8-byte word
+    /// to a boxed mask on the heap.
+    pub transforms: Option<u8>,
+    /// Discard patterns seen fewer than this many times, counting every
+    /// sample and transformed variant. Trims the one-off patterns of noisy
+    /// inputs, which slow the solve and rarely fit anywhere; `0` keeps all.
+    pub min_count: usize,
+    /// Keep only this many of the most frequent patterns, applied after
+    /// `min_count`; `0` keeps all. Bounds the tileset, and so the solve cost,
+    /// however noisy the input. Dropping patterns can leave some inputs
+    /// unsolvable, which is expected: raise the limit if contradictions pile
+    /// up.
+    pub max_patterns: usize,
+    /// Treat the output as a torus so opposite edges constrain each other.
+    pub wrap: bool,
+    /// Pick cells by raw candidate count instead of weighted Shannon entropy.
+    pub raw_entropy: bool,
+    /// Among equally constrained cells, prefer those with the most collapsed
+    /// neighbors so the output grows outward from finished regions instead
+    /// of starting scattered islands.
+    pub selection_bias: bool,
+    /// Number of past decisions kept for backtracking; `0` disables it and
+    /// contradictions fall straight through to a local reset.
+    pub max_backtrack_depth: usize,
+    /// Also constrain the four diagonal neighbors. Captures diagonal
+    /// structure at twice the adjacency memory and propagation cost.
+    pub diagonal: bool,
+    /// Same as `diagonal`, under the name to reach for when textures look
+    /// blocky: the corner overlaps smooth out seams that the four orthogonal
+    /// neighbors let through, for the same doubled cost.
+    pub smooth: bool,
+    /// Use a hexagonal grid with six neighbors per cell; see `new_hex`.
+    /// Cannot be combined with `diagonal`. Only the 180-degree rotation maps
+    /// hex neighbors onto each other, so `symmetry` adds at most that one.
+    pub hex: bool,
+    /// How cells on the output border are constrained. Ignored in wrap mode,
+    /// which has no border.
+    pub edge_mode: EdgeMode,
+    /// Where the first step on an untouched grid collapses.
+    pub start_mode: StartMode,
+    /// How the solver recovers from a contradiction.
+    pub contradiction_strategy: ContradictionStrategy,
+}
+
+/// Treatment of the out-of-bounds side of cells on the output border.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EdgeMode {
+    /// Any pattern may sit on the border.
+    Open,
+    /// A border cell only allows patterns that touched the matching side of
+    /// the input, so edges of the input stay edges of the output.
+    Constrained,
+}
+
+/// Pixel of a pattern that stands for it in `get_image_data`.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorSampling {
+    /// The top-left pixel, the one a cell contributes to the overlapping
+    /// output, so a collapsed grid reproduces the real pattern layout.
+    TopLeft,
+    /// The middle pixel, or the one right and below of the middle for even
+    /// sizes.
+    Center,
+    /// The mean of all the pattern's pixels, rounded.
+    Average,
+}
+
+/// How `get_image_data` shows a cell still in superposition.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplayMode {
+    /// The colors of the remaining tiles blended by weight.
+    Average,
+    /// The color of the heaviest remaining tile, for a crisper preview.
+    MostLikely,
+}
+
+/// Choice of the first cell collapsed after construction or a full reset.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StartMode {
+    /// The lowest-entropy cell, like every later step.
+    Random,
+    /// The center cell, so the output grows outward from the middle. Falls
+    /// back to `Random` if the center is already decided.
+    Center,
+}
+
+/// Recovery from a collapse whose propagation contradicts.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContradictionStrategy {
+    /// Clear the whole grid and start over.
+    FullReset,
+    /// Undo kept decisions if `max_backtrack_depth` allows, then clear a
+    /// square around the contradiction, growing it on repeated failures.
+    LocalReset,
+    /// Undo kept decisions, falling back to a full reset once they run out.
+    /// Needs a non-zero `max_backtrack_depth`, or it acts like `FullReset`.
+    Backtrack,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        EngineOptions {
+            output_size: 128,
+            output_width: None,
+            output_height: None,
+            tile_size: 2,
+            tile_width: None,
+            tile_height: None,
+            stride: 1,
+            seed: None,
+            symmetry: 1,
+            rotations: 4,
+            transforms: None,
+            min_count: 0,
+            max_patterns: 0,
+            wrap: false,
+            raw_entropy: false,
+            selection_bias: false,
+            max_backtrack_depth: 0,
+            diagonal: false,
+            smooth: false,
+            hex: false,
+            edge_mode: EdgeMode::Open,
+            start_mode: StartMode::Random,
+            contradiction_strategy: ContradictionStrategy::LocalReset,
+        }
+    }
+}
+
+impl EngineOptions {
+    // `transforms` if given, otherwise the flags equivalent to `symmetry`
+    // and `rotations`.
+    fn transform_flags(&self) -> u8 {
+        if let Some(flags) = self.transforms {
+            return flags;
+        }
+        let rotation = match (self.symmetry, self.rotations) {
+            (0, _) | (_, 1) => 0,
+            (_, 2) => ROT180,
+            _ => ROT90,
+        };
+        if self.symmetry == 2 { rotation | FLIP_H } else { rotation }
+    }
+}
+
+/// Counters describing the work done by the solver, returned by
+/// `WfcEngine::get_stats`.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SolveStats {
+    /// Cells collapsed by `step`.
+    pub steps: usize,
+    /// Contradictions backtracking couldn't resolve.
+    pub contradictions: usize,
+    /// Decisions undone by backtracking.
+    pub backtracks: usize,
+    /// Local resets attempted while resolving contradictions.
+    pub local_resets: usize,
+    /// Contradictions that escalated to clearing the whole grid.
+    pub full_resets: usize,
+    /// Times propagation narrowed a cell's options, a proxy for total work.
+    pub cells_narrowed: usize,
+}
+
+/// Cell chosen by the most recent `step`, returned by
+/// `WfcEngine::last_collapse`.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct CollapseInfo {
+    pub row: usize,
+    pub col: usize,
+    /// Tile `observe` picked for the cell.
+    pub tile: usize,
+}
+
+// Candidate cell in the entropy heap. Entries are pushed whenever a cell's
+// mask changes and never removed in place; `version` lets the pop side detect
+// and skip entries that no longer match the cell.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct HeapEntry {
+    entropy: f64,
+    idx: usize,
+    version: u32,
+}
+
+impl Eq for HeapEntry {}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed so `BinaryHeap` pops the lowest entropy first.
+        other.entropy.total_cmp(&self.entropy).then_with(|| other.idx.cmp(&self.idx))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// A collapse made by `step`, kept so a later contradiction can undo it.
+// `trail_start` is the length of `WfcEngine::trail` just before the collapse.
+#[derive(Clone, Copy, Debug)]
+struct Decision {
+    idx: usize,
+    tile: usize,
+    trail_start: usize,
+}
+
+// Entry of the log returned by `get_history`, serialized with a `kind` tag.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum HistoryEvent {
+    // `step` collapsed the cell to `tile`.
+    Collapse { row: usize, col: usize, tile: usize },
+    // Backtracking undid the collapse of the cell to `tile` and banned it there.
+    Undo { row: usize, col: usize, tile: usize },
+    // A contradiction cleared the `size` square around the cell.
+    LocalReset { row: usize, col: usize, size: usize },
+    // The whole grid was cleared.
+    Restart,
+}
+
+// Entry of the list returned by `get_image_deltas`.
+#[derive(Clone, Copy, Debug, Serialize)]
+struct PixelDelta {
+    index: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8,
+}
+
+// Snapshot written by `save_state`. The per-cell entropies are derived from
+// `matrix`, so they are recomputed on load rather than stored.
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    output_width: usize,
+    output_height: usize,
+    tile_count: usize,
+    matrix: Vec<TileMask>,
+    versions: Vec<u32>,
+    rng_seed: u64,
+    rng_draws: u64,
+    // The generator's own words, so loading never replays `rng_draws`.
+    rng_words: [u64; 4],
+    // `set_cell` pins as `(cell, tile)`, sorted by cell.
+    frozen: Vec<(usize, usize)>,
+    // The `set_active_mask` stencil and `set_ground` tile.
+    active: Vec<bool>,
+    ground: Option<usize>,
+    local_reset_size: usize,
+    local_reset_attempts: usize,
+}
+
 #[wasm_bindgen]
 pub struct WfcEngine {
-    output_size: usize,
+    output_width: usize,
+    output_height: usize,
+    tile_width: usize,
+    tile_height: usize,
+    // Pixels between neighboring cells as `(rows, cols)`: the stride for
+    // overlapping patterns, the whole tile for tile-based engines.
+    cell_step: (usize, usize),
     tiles: Vec<Tile>,
     weights: Vec<f32>,
-    adjacencies: Vec<HashMap<(isize, isize), u128>>,
-    matrix: Vec<u128>, 
+    // Frequency counts from extraction, the basis for `normalize_weights`.
+    pattern_counts: Vec<f32>,
+    // Neighbor offsets considered during propagation, and per tile the mask
+    // of tiles allowed next to it in each of those directions.
+    directions: &'static [(isize, isize)],
+    adjacencies: Vec<Vec<TileMask>>,
+    // Per tile and direction, the sorted tiles actually found next to it in
+    // the samples, a subset of `adjacencies`.
+    seen: Vec<Vec<Vec<usize>>>,
+    // Per-direction weight multipliers for `observe`; empty when off.
+    directional_bias: Vec<f32>,
+    matrix: MaskGrid,
     entropy_map: Vec<usize>,
-    rng: SmallRng,
-    all_flags: u128,
+    // Bumped on every mask write to invalidate older heap entries.
+    versions: Vec<u32>,
+    // Weighted Shannon entropy per cell, kept in sync with `matrix`.
+    shannon_map: Vec<f64>,
+    weight_log_weights: Vec<f64>,
+    // Color standing for each tile under `color_sampling`.
+    color_sampling: ColorSampling,
+    tile_colors: Vec<Color>,
+    display_mode: DisplayMode,
+    // Display color per cell, filled on demand by `cell_color` and dropped
+    // on every mask write so renders only recompute cells that changed.
+    display_colors: RefCell<Vec<Option<Color>>>,
+    // Cells whose display color may have changed since `get_image_deltas`
+    // last ran, listed once each and flagged in `dirty_flags`.
+    dirty: Vec<usize>,
+    dirty_flags: Vec<bool>,
+    raw_entropy: bool,
+    selection_bias: bool,
+    heap: BinaryHeap<HeapEntry>,
+    rng: EngineRng,
+    all_flags: TileMask,
+    edge_mode: EdgeMode,
+    start_mode: StartMode,
+    // No cell has been collapsed since the grid was last cleared.
+    untouched: bool,
+    // Cells whose tile was chosen by a collapse or reported by
+    // `collapse_forced`, as opposed to ones propagation narrowed to a single
+    // tile. Cleared whenever the cell's mask widens again.
+    finalized: Vec<bool>,
+    // Tile pinned along the bottom row by `set_ground`.
+    ground: Option<usize>,
+    // Cells pinned by `set_cell`, re-applied whenever the grid is cleared.
+    frozen: HashMap<usize, usize>,
+    // Weight multipliers per cell and tile from `set_preference`.
+    preferences: HashMap<usize, Vec<(usize, f32)>>,
+    // Cells taking part in generation; see `set_active_mask`.
+    active: Vec<bool>,
+    // Per side of the output, indexed like `DIRECTIONS`, the tiles allowed
+    // in cells along that side under `EdgeMode::Constrained`.
+    border_masks: Vec<TileMask>,
     stack: Vec<(usize, usize)>,
+    // Collapse whose propagation `set_propagation_budget` cut short, with
+    // the rest still on `stack`.
+    pending: Option<(usize, usize)>,
+    propagation_budget: usize,
+    wrap: bool,
     
     // Backtracking state
     local_reset_size: usize,
     local_reset_attempts: usize,
+    // Escalation of local resets; see `set_reset_params`.
+    reset_initial_size: usize,
+    reset_growth: usize,
+    reset_attempts_before_growth: usize,
+    // Counters since the last `reset()`.
+    stats: SolveStats,
+    // Cell whose collapse caused the most recent counted contradiction.
+    last_contradiction_pos: Option<(usize, usize)>,
+    // The `set_on_contradiction` callback declined recovery, so the grid is
+    // left contradicted and `step` does nothing until it is cleared.
+    halted: bool,
+    // Cell and tile picked by the latest `step`.
+    last_collapse: Option<CollapseInfo>,
+    max_backtrack_depth: usize,
+    strategy: ContradictionStrategy,
+    decisions: VecDeque<Decision>,
+    // Previous mask of every cell changed since the oldest kept decision.
+    trail: Vec<(usize, TileMask)>,
+    on_collapse: Option<js_sys::Function>,
+    on_contradiction: Option<js_sys::Function>,
+    // Search log, recorded only once `enable_history` has been called.
+    history: Option<Vec<HistoryEvent>>,
 }
 
 #[wasm_bindgen]
 impl WfcEngine {
     #[wasm_bindgen(constructor)]
-    pub fn new(input_colors: JsValue, output_size: usize, tile_size: usize) -> Result<WfcEngine, JsValue> {
+    pub fn new(input_colors: JsValue, output_size: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        let input: Vec<Vec<Color>> = serde_wasm_bindgen::from_value(input_colors)?;
+        Self::build(&[&input], &EngineOptions { output_size, tile_size, ..EngineOptions::default() })
+    }
+
+    /// Constructs an engine with a `width` x `height` output grid.
+    pub fn new_rect(input_colors: JsValue, width: usize, height: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        let input: Vec<Vec<Color>> = serde_wasm_bindgen::from_value(input_colors)?;
+        Self::build(&[&input], &EngineOptions {
+            output_width: Some(width),
+            output_height: Some(height),
+            tile_size,
+            ..EngineOptions::default()
+        })
+    }
+
+    /// Like `new`, but seeds the RNG so that the same input and seed always
+    /// produce the same output for the same sequence of `step()` calls.
+    pub fn new_seeded(input_colors: JsValue, output_size: usize, tile_size: usize, seed: u64) -> Result<WfcEngine, WfcError> {
+        let input: Vec<Vec<Color>> = serde_wasm_bindgen::from_value(input_colors)?;
+        Self::build(&[&input], &EngineOptions { output_size, tile_size, seed: Some(seed), ..EngineOptions::default() })
+    }
+
+    /// Constructs an engine on a hexagonal grid. Both the input and the
+    /// output are read in axial coordinates: row `r`, column `q`, with the
+    /// neighbors of `(r, q)` at `(r, q ± 1)`, `(r ± 1, q)`, `(r - 1, q + 1)`
+    /// and `(r + 1, q - 1)`. The row-major output is therefore a rhombus;
+    /// draw row `r` shifted right by `r / 2` cells to lay it out as hexes.
+    pub fn new_hex(input_colors: JsValue, output_size: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        let input: Vec<Vec<Color>> = serde_wasm_bindgen::from_value(input_colors)?;
+        Self::build(&[&input], &EngineOptions { output_size, tile_size, hex: true, ..EngineOptions::default() })
+    }
+
+    /// Constructs an engine from a flat `[r, g, b, a, ...]` buffer such as a
+    /// canvas `ImageData`, skipping the nested-array serde round-trip. Alpha
+    /// is kept, so transparent cutouts survive into the output.
+    pub fn from_rgba(bytes: &[u8], width: usize, height: usize, output_size: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        if width == 0 || height == 0 || bytes.len() != width * height * 4 {
+            return Err(WfcError::InvalidPixelBuffer { width, height, channels: 4, len: bytes.len() });
+        }
+
+        let input: Vec<Vec<Color>> = bytes
+            .chunks_exact(width * 4)
+            .map(|row| row.chunks_exact(4).map(|p| Color { r: p[0], g: p[1], b: p[2], a: p[3] }).collect())
+            .collect();
+        Self::build(&[&input], &EngineOptions { output_size, tile_size, ..EngineOptions::default() })
+    }
+
+    /// Constructs an engine from a flat buffer of one luminance byte per
+    /// pixel, such as a heightmap. Each value `v` becomes the opaque gray
+    /// `(v, v, v)`, so the image data renders back as grayscale.
+    pub fn from_gray(values: &[u8], width: usize, height: usize, output_size: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        if width == 0 || height == 0 || values.len() != width * height {
+            return Err(WfcError::InvalidPixelBuffer { width, height, channels: 1, len: values.len() });
+        }
+
+        let input: Vec<Vec<Color>> = values
+            .chunks_exact(width)
+            .map(|row| row.iter().map(|&v| Color { r: v, g: v, b: v, a: 255 }).collect())
+            .collect();
+        Self::build(&[&input], &EngineOptions { output_size, tile_size, ..EngineOptions::default() })
+    }
+
+    /// Constructs an engine from a grid of integer labels, e.g. tile IDs of an
+    /// abstract tilemap, `width * height` of them in row-major order.
+    /// Patterns match on exact labels. Read the result with `get_tile_grid`
+    /// and map tiles back to labels with `get_tile_labels`; the image data
+    /// shows each label as an arbitrary but stable color.
+    pub fn from_labels(grid: &[i32], width: usize, height: usize, output_size: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        if width == 0 || height == 0 || grid.len() != width * height {
+            return Err(WfcError::InvalidPixelBuffer { width, height, channels: 1, len: grid.len() });
+        }
+
+        let input: Vec<Vec<Color>> = grid
+            .chunks_exact(width)
+            .map(|row| row.iter().map(|&label| label_color(label)).collect())
+            .collect();
+        Self::build(&[&input], &EngineOptions { output_size, tile_size, ..EngineOptions::default() })
+    }
+
+    /// Constructs an engine whose tileset is the union of the patterns in
+    /// several example images. `samples` is an array of 2D color grids like
+    /// the one `new` takes; they may differ in size, and a pattern found in
+    /// more than one of them gets the sum of its counts as weight.
+    pub fn from_samples(samples: JsValue, output_size: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        let samples: Vec<Vec<Vec<Color>>> = serde_wasm_bindgen::from_value(samples)?;
+        let samples: Vec<&[Vec<Color>]> = samples.iter().map(Vec::as_slice).collect();
+        Self::build(&samples, &EngineOptions { output_size, tile_size, ..EngineOptions::default() })
+    }
+
+    /// Constructs an engine from an `EngineOptions`-shaped JS object, e.g.
+    /// `{ output_size: 64, tile_size: 3, symmetry: 2 }`.
+    pub fn with_options(input_colors: JsValue, options: JsValue) -> Result<WfcEngine, WfcError> {
         let input: Vec<Vec<Color>> = serde_wasm_bindgen::from_value(input_colors)?;
-        let rng = SmallRng::from_entropy();
+        let options: EngineOptions = serde_wasm_bindgen::from_value(options)?;
+        Self::build(&[&input], &options)
+    }
+
+    /// Constructs a tile-based engine from hand-authored rules instead of
+    /// patterns extracted from an example. `tiles` is an array of equally
+    /// sized 2D color grids and `weights` their relative frequencies.
+    /// `rules` is an array of `[tile_a, tile_b, direction]` triples, each
+    /// allowing `tile_b` next to `tile_a` in `direction` (0 up, 1 down,
+    /// 2 left, 3 right); the mirrored pair is implied, so `[0, 1, 3]` also
+    /// allows tile 0 left of tile 1. Pairs not listed are forbidden.
+    pub fn from_tiles_and_rules(
+        tiles: JsValue,
+        weights: Vec<f32>,
+        rules: JsValue,
+        output_size: usize,
+    ) -> Result<WfcEngine, WfcError> {
+        let tiles: Vec<Tile> = serde_wasm_bindgen::from_value(tiles)?;
+        let rules: Vec<(usize, usize, usize)> = serde_wasm_bindgen::from_value(rules)?;
+        let options = EngineOptions { output_size, ..EngineOptions::default() };
+        let adjacencies = rule_adjacencies(&tiles, &weights, &rules)?;
+        // Authored tiles carry no border information, so any of them may
+        // sit on a constrained edge.
+        let edges = vec![[true; 4]; tiles.len()];
+        // Nor any example; the rules stand in for it in directional bias.
+        let seen = adjacency_lists(&adjacencies);
+        // The tiles sit side by side rather than overlapping.
+        let cell_step = (tiles[0].len(), tiles[0][0].len());
+        Self::assemble(Patterns { tiles, weights, edges }, &DIRECTIONS, adjacencies, seen, cell_step, &options)
+    }
 
-        let (tiles, weights) = extract_tiles(&input, tile_size);
-        if tiles.len() > 128 {
-            return Err(JsValue::from_str("Too many unique patterns. Max 128."));
+    /// Constructs a tile-based engine from Wang tiles, whose sides carry
+    /// color codes and may touch wherever the codes match. `edges[i]` is
+    /// `[n, e, s, w]` for tile `i`, and `tiles_rgba[i]` its flat RGBA pixels,
+    /// `tile_size * tile_size * 4` bytes, used only for rendering. Every
+    /// tile gets weight `1`; adjust with `set_weight`.
+    pub fn from_wang(edges: JsValue, tiles_rgba: JsValue, output_size: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        let edges: Vec<[u32; 4]> = serde_wasm_bindgen::from_value(edges)?;
+        let tiles_rgba: Vec<Vec<u8>> = serde_wasm_bindgen::from_value(tiles_rgba)?;
+        Self::build_wang(&edges, &tiles_rgba, output_size, tile_size)
+    }
+
+    // `from_wang` once the JS arguments are deserialized.
+    fn build_wang(edges: &[[u32; 4]], tiles_rgba: &[Vec<u8>], output_size: usize, tile_size: usize) -> Result<WfcEngine, WfcError> {
+        if tile_size == 0 {
+            return Err(WfcError::InvalidTileSize { tile_width: 0, tile_height: 0 });
+        }
+        if edges.is_empty() {
+            return Err(WfcError::EmptyInput);
+        }
+        if edges.len() != tiles_rgba.len() {
+            return Err(WfcError::InvalidArgument {
+                reason: format!("{} edge sets given for {} tiles", edges.len(), tiles_rgba.len()),
+            });
         }
+        let side = tile_size * 4;
+        let mut tiles = Vec::with_capacity(tiles_rgba.len());
+        for bytes in tiles_rgba {
+            if bytes.len() != side * tile_size {
+                return Err(WfcError::InvalidPixelBuffer { width: tile_size, height: tile_size, channels: 4, len: bytes.len() });
+            }
+            let tile: Tile = bytes
+                .chunks_exact(side)
+                .map(|row| row.chunks_exact(4).map(|p| Color { r: p[0], g: p[1], b: p[2], a: p[3] }).collect())
+                .collect();
+            tiles.push(tile);
+        }
+
+        let options = EngineOptions { output_size, ..EngineOptions::default() };
+        let adjacencies = wang_adjacencies(edges);
+        let seen = adjacency_lists(&adjacencies);
+        let patterns = Patterns {
+            weights: vec![1.0; tiles.len()],
+            edges: vec![[true; 4]; tiles.len()],
+            tiles,
+        };
+        Self::assemble(patterns, &DIRECTIONS, adjacencies, seen, (tile_size, tile_size), &options)
+    }
 
-        let all_flags = if tiles.len() == 128 {
-            !0u128
+    fn build(samples: &[&[Vec<Color>]], options: &EngineOptions) -> Result<WfcEngine, WfcError> {
+        let patterns = extract_patterns(samples, options)?;
+        let diagonal = options.diagonal || options.smooth;
+        let directions: &'static [(isize, isize)] = if options.hex {
+            &HEX_DIRECTIONS
+        } else if diagonal {
+            &DIRECTIONS_8
         } else {
-            (1u128 << tiles.len()) - 1
+            &DIRECTIONS
         };
+        let adjacencies = compute_adjacencies(&patterns.tiles, directions, options.stride);
+        let seen = observed_neighbors(samples, &patterns.tiles, directions, options.stride);
+        Self::assemble(patterns, directions, adjacencies, seen, (options.stride, options.stride), options)
+    }
 
-        let adjacencies = compute_adjacencies(&tiles);
+    // Sets up an engine over a finished tileset and its rules, with the
+    // cells of the output `cell_step` pixels apart as `(rows, cols)`. Only
+    // the output, solver and seed fields of `options` are read here.
+    fn assemble(
+        patterns: Patterns,
+        directions: &'static [(isize, isize)],
+        adjacencies: Vec<Vec<TileMask>>,
+        seen: Vec<Vec<Vec<usize>>>,
+        cell_step: (usize, usize),
+        options: &EngineOptions,
+    ) -> Result<WfcEngine, WfcError> {
+        let Patterns { tiles, weights, edges } = patterns;
+        let tile_width = tiles[0][0].len();
+        let tile_height = tiles[0].len();
+        let output_width = options.output_width.unwrap_or(options.output_size);
+        let output_height = options.output_height.unwrap_or(options.output_size);
+        if output_width == 0 || output_height == 0 {
+            return Err(WfcError::InvalidOutputSize { width: output_width, height: output_height });
+        }
+        let rng = match options.seed {
+            Some(seed) => EngineRng::new(seed),
+            None => EngineRng::from_entropy(),
+        };
+        let all_flags = TileMask::full(tiles.len());
+        let border_masks = (0..4)
+            .map(|side| {
+                let mut mask = TileMask::empty(tiles.len());
+                for (i, e) in edges.iter().enumerate() {
+                    if e[side] {
+                        mask.insert(i);
+                    }
+                }
+                mask
+            })
+            .collect();
 
-        let matrix = vec![all_flags; output_size * output_size];
-        let entropy_map = vec![tiles.len(); output_size * output_size];
+        let matrix = MaskGrid::new(tiles.len(), output_width * output_height, &all_flags);
+        let entropy_map = vec![tiles.len(); output_width * output_height];
+        let cells = output_width * output_height;
 
-        Ok(WfcEngine {
-            output_size,
+        let mut engine = WfcEngine {
+            output_width,
+            output_height,
+            tile_width,
+            tile_height,
+            cell_step,
             tiles,
+            pattern_counts: weights.clone(),
             weights,
+            directions,
             adjacencies,
+            seen,
+            directional_bias: Vec::new(),
             matrix,
             entropy_map,
+            versions: vec![0; cells],
+            shannon_map: vec![0.0; cells],
+            weight_log_weights: Vec::new(),
+            color_sampling: ColorSampling::TopLeft,
+            tile_colors: Vec::new(),
+            display_mode: DisplayMode::Average,
+            display_colors: RefCell::new(vec![None; cells]),
+            dirty: (0..cells).collect(),
+            dirty_flags: vec![true; cells],
+            raw_entropy: options.raw_entropy,
+            selection_bias: options.selection_bias,
+            heap: BinaryHeap::with_capacity(cells),
             rng,
             all_flags,
-            stack: Vec::with_capacity(output_size * output_size),
+            edge_mode: options.edge_mode,
+            start_mode: options.start_mode,
+            untouched: true,
+            finalized: vec![false; cells],
+            ground: None,
+            frozen: HashMap::new(),
+            preferences: HashMap::new(),
+            active: vec![true; cells],
+            border_masks,
+            stack: Vec::with_capacity(output_width * output_height),
+            pending: None,
+            propagation_budget: 0,
+            wrap: options.wrap,
             local_reset_size: 8,
             local_reset_attempts: 0,
-        })
+            reset_initial_size: 8,
+            reset_growth: 4,
+            reset_attempts_before_growth: 8,
+            stats: SolveStats::default(),
+            last_contradiction_pos: None,
+            halted: false,
+            last_collapse: None,
+            max_backtrack_depth: options.max_backtrack_depth,
+            strategy: options.contradiction_strategy,
+            decisions: VecDeque::new(),
+            trail: Vec::new(),
+            on_collapse: None,
+            on_contradiction: None,
+            history: None,
+        };
+        engine.tile_colors = sample_colors(&engine.tiles, engine.color_sampling);
+        engine.update_weight_cache();
+        if !engine.apply_initial_constraints() {
+            return Err(WfcError::UnsatisfiableBorder);
+        }
+        Ok(engine)
     }
 
+    /// Collapses the next cell and propagates the choice. Returns `false` once
+    /// there is nothing left to do: the grid is complete, or a contradiction
+    /// callback declined recovery and the grid is halted until `reset()`.
     pub fn step(&mut self) -> bool {
-        let next_pos = self.find_lowest_entropy();
+        if self.halted {
+            return false;
+        }
+        if self.pending.is_some() {
+            self.resume_propagation(self.propagation_budget);
+            return true;
+        }
+        let start = if self.untouched && self.start_mode == StartMode::Center {
+            self.center_cell()
+        } else {
+            None
+        };
+        let next_pos = start.or_else(|| self.find_lowest_entropy());
         match next_pos {
             Some(idx) => {
-                let mask = self.matrix[idx];
-                let chosen_tile_idx = self.observe(mask);
-                self.matrix[idx] = 1 << chosen_tile_idx;
-                self.entropy_map[idx] = 1;
-                
-                let row = idx / self.output_size;
-                let col = idx % self.output_size;
-                self.stack.push((row, col));
-                
-                if !self.propagate() {
-                    self.handle_contradiction(row, col);
-                    return true;
-                }
+                self.stats.steps += 1;
+                let chosen_tile_idx = self.observe(idx);
+                self.last_collapse = Some(CollapseInfo {
+                    row: idx / self.output_width,
+                    col: idx % self.output_width,
+                    tile: chosen_tile_idx,
+                });
+                self.collapse(idx, chosen_tile_idx, self.propagation_budget);
                 true
             }
             None => false, // Done
         }
     }
 
-    fn handle_contradiction(&mut self, row: usize, col: usize) {
-        self.local_reset_attempts += 1;
-        
-        if self.local_reset_attempts > 8 {
-            self.local_reset_attempts = 0;
-            self.local_reset_size += 4;
+    /// Like `step`, but collapses `(row, col)` to `tile_idx` instead of
+    /// letting the solver choose, e.g. for a paint-as-you-go editor.
+    /// Contradictions are handled as in `step`. Returns `false` without
+    /// changing anything if the position is invalid or the tile is not
+    /// allowed there.
+    pub fn step_with(&mut self, row: usize, col: usize, tile_idx: usize) -> bool {
+        self.resume_propagation(0);
+        if self.halted || row >= self.output_height || col >= self.output_width {
+            return false;
         }
+        let idx = row * self.output_width + col;
+        if tile_idx >= self.tiles.len() || !self.matrix.get(idx).contains(tile_idx) || !self.active[idx] {
+            return false;
+        }
+        self.collapse(idx, tile_idx, 0);
+        true
+    }
 
-        // If area too big, just reset everything
-        if self.local_reset_size > self.output_size {
-            self.reset();
-        } else {
-            self.reset_local(row, col, self.local_reset_size);
+    // Collapses cell `idx` to `tile` and propagates, stopping after `budget`
+    // cells (`0` for no limit) with the rest left to `resume_propagation`.
+    fn collapse(&mut self, idx: usize, tile: usize, budget: usize) {
+        self.untouched = false;
+        self.push_decision(idx, tile);
+        self.set_mask(idx, TileMask::single(self.tiles.len(), tile));
+        self.finalized[idx] = true;
+
+        let row = idx / self.output_width;
+        let col = idx % self.output_width;
+        self.notify_collapse(row, col);
+        self.record(HistoryEvent::Collapse { row, col, tile });
+        self.stack.push((row, col));
+        self.pending = Some((row, col));
+        self.resume_propagation(budget);
+    }
+
+    // Carries on with the propagation of the collapse in `pending`, if any,
+    // and recovers if it contradicts.
+    fn resume_propagation(&mut self, budget: usize) {
+        let Some((row, col)) = self.pending else {
+            return;
+        };
+        let Some(consistent) = self.propagate_within(budget) else {
+            return;
+        };
+        self.pending = None;
+        if !consistent && (self.strategy == ContradictionStrategy::FullReset || !self.backtrack()) {
+            self.handle_contradiction(row, col);
         }
     }
 
-    fn reset_local(&mut self, row: usize, col: usize, size: usize) {
-        let half = (size / 2) as isize;
-        let r_center = row as isize;
-        let c_center = col as isize;
+    /// Caps how many cells the propagation of one `step` may visit. Past the
+    /// cap the step returns early and the following steps finish the
+    /// propagation before collapsing anything new, spreading one large
+    /// cascade over several frames at the cost of more `step` calls. `0`,
+    /// the default, means no limit. Other calls that change the grid first
+    /// finish any propagation left over.
+    pub fn set_propagation_budget(&mut self, budget: usize) {
+        self.propagation_budget = budget;
+    }
 
-        for dr in -half..half {
-            for dc in -half..half {
-                let nr = r_center + dr;
-                let nc = c_center + dc;
+    /// Registers a function called as `callback(row, col)` each time `step`
+    /// collapses a cell, before the choice is propagated. Exceptions thrown
+    /// by the callback are ignored. The engine is borrowed during the call,
+    /// so the callback must not call back into it.
+    pub fn set_on_collapse(&mut self, callback: js_sys::Function) {
+        self.on_collapse = Some(callback);
+    }
 
-                if nr >= 0 && nr < self.output_size as isize && nc >= 0 && nc < self.output_size as isize {
-                    let idx = nr as usize * self.output_size + nc as usize;
-                    self.matrix[idx] = self.all_flags;
-                    self.entropy_map[idx] = self.tiles.len();
-                }
-            }
+    /// Removes the callback registered with `set_on_collapse`.
+    pub fn clear_on_collapse(&mut self) {
+        self.on_collapse = None;
+    }
+
+    /// Starts logging the search for `get_history`: every collapse, every
+    /// collapse undone by backtracking, and every local or full reset.
+    pub fn enable_history(&mut self) {
+        self.history.get_or_insert_with(Vec::new);
+    }
+
+    /// Events recorded since `enable_history`, oldest first, as objects with
+    /// a `kind` of `"collapse"` or `"undo"` (with `row`, `col`, `tile`),
+    /// `"local_reset"` (with `row`, `col`, `size`) or `"restart"`. Replaying
+    /// them in order reproduces the path the solver took.
+    pub fn get_history(&self) -> JsValue {
+        let events = self.history.as_deref().unwrap_or_default();
+        serde_wasm_bindgen::to_value(events).expect("history events always convert")
+    }
+
+    fn record(&mut self, event: HistoryEvent) {
+        if let Some(history) = &mut self.history {
+            history.push(event);
         }
-        self.stack.clear();
-        
-        // After local reset, we need to re-propagate constraints from the boundary 
-        // of the reset area into the reset area. For simplicity in this high-perf version,
-        // we just clear the stack and let the next observe/propagate cycle handle it.
-        // A more perfect backtracking would re-propagate from fixed neighbors.
     }
 
-    fn find_lowest_entropy(&mut self) -> Option<usize> {
-        let mut min_entropy = usize::MAX;
-        let mut candidates = Vec::new();
+    /// Registers a function called as `callback(row, col)` with the cell
+    /// whose collapse caused a contradiction that backtracking couldn't
+    /// resolve, before any reset. Returning `false` skips the recovery and
+    /// leaves the grid as propagation left it, contradicted cell included,
+    /// e.g. to pause and show the failure; `step` then does nothing until
+    /// `reset()`. Any other return
+    /// value, or a thrown exception, lets the recovery go ahead. As with
+    /// `set_on_collapse`, the callback must not call back into the engine.
+    pub fn set_on_contradiction(&mut self, callback: js_sys::Function) {
+        self.on_contradiction = Some(callback);
+    }
 
-        for i in 0..self.matrix.len() {
-            let e = self.entropy_map[i];
-            if e > 1 {
-                if e < min_entropy {
-                    min_entropy = e;
-                    candidates.clear();
-                    candidates.push(i);
-                } else if e == min_entropy {
-                    candidates.push(i);
-                }
-            }
+    /// Removes the callback registered with `set_on_contradiction`.
+    pub fn clear_on_contradiction(&mut self) {
+        self.on_contradiction = None;
+    }
+
+    // Whether the contradiction callback, if any, lets recovery go ahead.
+    fn allow_recovery(&self, row: usize, col: usize) -> bool {
+        let Some(callback) = &self.on_contradiction else {
+            return true;
+        };
+        callback
+            .call2(&JsValue::NULL, &JsValue::from(row as u32), &JsValue::from(col as u32))
+            .map_or(true, |ret| ret.as_bool() != Some(false))
+    }
+
+    fn notify_collapse(&self, row: usize, col: usize) {
+        if let Some(callback) = &self.on_collapse {
+            let _ = callback.call2(&JsValue::NULL, &JsValue::from(row as u32), &JsValue::from(col as u32));
         }
+    }
 
-        if candidates.is_empty() {
-            None
-        } else {
-            Some(candidates[self.rng.gen_range(0..candidates.len())])
+    /// Finalizes the cells propagation has narrowed to a single tile without
+    /// a collapse choosing it, e.g. the neighbors forced by a few `set_cell`
+    /// pins. Each is reported like a `step` collapse: `set_on_collapse`
+    /// fires, history records it and `last_collapse` points at it. Their
+    /// constraints are then propagated again, handling contradictions as
+    /// `step` does. Pins themselves are not reported. Returns the number of
+    /// cells finalized.
+    pub fn collapse_forced(&mut self) -> usize {
+        self.resume_propagation(0);
+        let forced: Vec<usize> = (0..self.matrix.len())
+            .filter(|&i| self.entropy_map[i] == 1 && self.active[i] && !self.finalized[i] && !self.frozen.contains_key(&i))
+            .collect();
+        for &idx in &forced {
+            let tile = self.matrix.get(idx).single_tile().expect("entropy 1 means one tile");
+            let (row, col) = (idx / self.output_width, idx % self.output_width);
+            self.finalized[idx] = true;
+            self.untouched = false;
+            self.notify_collapse(row, col);
+            self.record(HistoryEvent::Collapse { row, col, tile });
+            self.last_collapse = Some(CollapseInfo { row, col, tile });
+            self.stack.push((row, col));
+            self.pending = Some((row, col));
         }
+        self.resume_propagation(0);
+        forced.len()
     }
 
-    fn observe(&mut self, mask: u128) -> usize {
-        let mut options = Vec::new();
-        let mut total_weight = 0.0;
-        for i in 0..self.tiles.len() {
-            if (mask & (1 << i)) != 0 {
-                options.push(i);
-                total_weight += self.weights[i];
+    /// Performs up to `n` steps. Returns `true` while work remains and
+    /// `false` once the grid is fully collapsed, including on the call whose
+    /// last step completed it, or as soon as `step` stops early.
+    pub fn step_n(&mut self, n: usize) -> bool {
+        for _ in 0..n {
+            if !self.step() {
+                return false;
             }
         }
+        self.pending.is_some() || !self.is_complete()
+    }
+
+    /// Pins the cell at `(row, col)` to `tile_idx` and propagates the
+    /// constraint. Call before stepping. The pin survives the resets made
+    /// while resolving contradictions and is only dropped by `reset()`.
+    /// Returns `false` if the position or tile is invalid, the tile is no
+    /// longer allowed there, or propagation hits a contradiction; the grid is
+    /// then left as-is, without the pin, so the caller can `reset()`.
+    pub fn set_cell(&mut self, row: usize, col: usize, tile_idx: usize) -> bool {
+        self.resume_propagation(0);
+        if row >= self.output_height || col >= self.output_width || tile_idx >= self.tiles.len() {
+            return false;
+        }
 
-        if options.is_empty() {
-            // This shouldn't happen if propagate works right, but safety first
-            return 0;
+        let idx = row * self.output_width + col;
+        if !self.matrix.get(idx).contains(tile_idx) || !self.active[idx] {
+            return false;
         }
 
-        let mut r = self.rng.gen_range(0.0..total_weight);
-        for &idx in &options {
-            r -= self.weights[idx];
-            if r <= 0.0 {
-                return idx;
-            }
+        self.untouched = false;
+        self.set_mask(idx, TileMask::single(self.tiles.len(), tile_idx));
+        self.stack.push((row, col));
+        if !self.propagate() {
+            return false;
         }
-        options[options.len() - 1]
+        self.frozen.insert(idx, tile_idx);
+        true
     }
 
-    fn propagate(&mut self) -> bool {
-        while let Some((r, c)) = self.stack.pop() {
-            let current_mask = self.matrix[r * self.output_size + c];
+    /// Makes `step` favor `tile_idx` at `(row, col)` without forbidding the
+    /// alternatives: its weight there is multiplied by `strength` whenever the
+    /// cell is observed, so `4.0` makes it four times as likely and `0.25`
+    /// discourages it. Propagation still decides what is allowed, so the cell
+    /// may end up with another tile. Replaces any earlier preference for the
+    /// same cell and tile; a strength of `1.0` removes it. Preferences survive
+    /// `reset()`. Returns `false` and changes nothing if the position or tile
+    /// is invalid or `strength` is negative or not finite.
+    pub fn set_preference(&mut self, row: usize, col: usize, tile_idx: usize, strength: f32) -> bool {
+        if row >= self.output_height || col >= self.output_width || tile_idx >= self.tiles.len() {
+            return false;
+        }
+        if !strength.is_finite() || strength < 0.0 {
+            return false;
+        }
 
-            for &(dr, dc) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
-                let nr = r as isize + dr;
-                let nc = c as isize + dc;
+        let idx = row * self.output_width + col;
+        let prefs = self.preferences.entry(idx).or_default();
+        prefs.retain(|&(t, _)| t != tile_idx);
+        if strength != 1.0 {
+            prefs.push((tile_idx, strength));
+        }
+        if prefs.is_empty() {
+            self.preferences.remove(&idx);
+        }
+        true
+    }
 
-                if nr >= 0 && nr < self.output_size as isize && nc >= 0 && nc < self.output_size as isize {
-                    let nr = nr as usize;
-                    let nc = nc as usize;
-                    let n_idx = nr * self.output_size + nc;
-                    let n_mask = self.matrix[n_idx];
+    /// Drops every preference set by `set_preference`.
+    pub fn clear_preferences(&mut self) {
+        self.preferences.clear();
+    }
 
-                    if self.entropy_map[n_idx] <= 1 {
-                        continue;
-                    }
+    /// Whether `set_cell(row, col, tile_idx)` would succeed, found by
+    /// propagating the pin on a scratch copy of the cells it reaches. Leaves
+    /// the engine untouched.
+    pub fn can_set_cell(&self, row: usize, col: usize, tile_idx: usize) -> bool {
+        self.dry_collapse(row, col, tile_idx).is_some_and(|(_, consistent)| consistent)
+    }
 
-                    let mut allowed_mask = 0u128;
-                    for i in 0..self.tiles.len() {
-                        if (current_mask & (1 << i)) != 0 {
-                            allowed_mask |= self.adjacencies[i].get(&(dr, dc)).cloned().unwrap_or(0);
-                        }
-                    }
+    /// The `get_entropy_map` that collapsing `(row, col)` to `tile_idx` and
+    /// propagating would produce, computed on a scratch copy so the engine is
+    /// left untouched, e.g. to preview candidate choices in a "what if" UI.
+    /// Propagation stops at the first contradiction, whose cell shows `0`.
+    /// Empty if the position or tile is invalid or the tile is not allowed
+    /// there.
+    pub fn preview_step(&self, row: usize, col: usize, tile_idx: usize) -> Vec<u32> {
+        let Some((scratch, _)) = self.dry_collapse(row, col, tile_idx) else {
+            return Vec::new();
+        };
+        let mut map = self.get_entropy_map();
+        for (idx, mask) in scratch {
+            map[idx] = mask.count_ones();
+        }
+        map
+    }
 
-                    let updated_mask = n_mask & allowed_mask;
-                    if updated_mask == 0 {
-                        return false; 
-                    }
+    // Collapses `(row, col)` to `tile_idx` and propagates on a scratch map of
+    // changed masks standing in for `matrix` entries, returning the map and
+    // whether it stayed consistent; a contradiction leaves the emptied mask
+    // in the map. `None` if the collapse itself isn't allowed.
+    fn dry_collapse(&self, row: usize, col: usize, tile_idx: usize) -> Option<(HashMap<usize, TileMask>, bool)> {
+        if row >= self.output_height || col >= self.output_width || tile_idx >= self.tiles.len() {
+            return None;
+        }
+        let idx = row * self.output_width + col;
+        if !self.matrix.get(idx).contains(tile_idx) || !self.active[idx] {
+            return None;
+        }
 
-                    if updated_mask != n_mask {
-                        self.matrix[n_idx] = updated_mask;
-                        self.entropy_map[n_idx] = updated_mask.count_ones() as usize;
-                        self.stack.push((nr, nc));
-                    }
+        let mut scratch: HashMap<usize, TileMask> = HashMap::new();
+        scratch.insert(idx, TileMask::single(self.tiles.len(), tile_idx));
+        let mut stack = vec![(row, col)];
+        while let Some((r, c)) = stack.pop() {
+            let current_mask = scratch[&(r * self.output_width + c)].clone();
+            for (dir, &(dr, dc)) in self.directions.iter().enumerate() {
+                let Some((nr, nc)) = self.neighbor(r, c, dr, dc) else {
+                    continue;
+                };
+                let n_idx = nr * self.output_width + nc;
+                let mask = scratch.get(&n_idx).map_or_else(|| self.matrix.get(n_idx), Cow::Borrowed);
+                if mask.count_ones() <= 1 || !self.active[n_idx] {
+                    continue;
+                }
+
+                let mut allowed_mask = TileMask::empty(self.tiles.len());
+                for i in current_mask.iter() {
+                    allowed_mask |= &self.adjacencies[i][dir];
+                }
+                let updated_mask = &*mask & &allowed_mask;
+                if updated_mask.is_empty() {
+                    scratch.insert(n_idx, updated_mask);
+                    return Some((scratch, false));
+                }
+                if updated_mask != *mask {
+                    scratch.insert(n_idx, updated_mask);
+                    stack.push((nr, nc));
                 }
             }
         }
-        true
+        Some((scratch, true))
     }
 
-    pub fn reset(&mut self) {
-        for i in 0..self.matrix.len() {
-            self.matrix[i] = self.all_flags;
-            self.entropy_map[i] = self.tiles.len();
+    /// Pins a whole border, e.g. to continue a chunk of an endless map: side
+    /// `0`..`3` is top, bottom, left or right, and `tiles` holds one tile
+    /// index per cell along it (left to right or top to bottom), `-1` to
+    /// leave a cell free; other negative values are an error. Seeding the left edge from the previous chunk's
+    /// `get_tile_grid` right column gives a seamless join when the chunks are
+    /// placed to overlap by that one column. Pins behave as with `set_cell`.
+    /// Errors without touching the grid if an argument is invalid or a tile
+    /// isn't allowed where it's placed; if propagation contradicts, the grid
+    /// is left as-is so the caller can `reset()`.
+    pub fn seed_edge(&mut self, side: u32, tiles: &[i32]) -> Result<(), WfcError> {
+        self.resume_propagation(0);
+        let (w, h) = (self.output_width, self.output_height);
+        let cells: Vec<usize> = match side {
+            0 => (0..w).collect(),
+            1 => (0..w).map(|c| (h - 1) * w + c).collect(),
+            2 => (0..h).map(|r| r * w).collect(),
+            3 => (0..h).map(|r| r * w + w - 1).collect(),
+            _ => return Err(WfcError::InvalidArgument { reason: format!("side must be 0 to 3, got {}", side) }),
+        };
+        if tiles.len() != cells.len() {
+            return Err(WfcError::BufferSizeMismatch { expected: cells.len(), found: tiles.len() });
+        }
+
+        let mut pins = Vec::new();
+        for (&idx, &tile) in cells.iter().zip(tiles) {
+            if tile == -1 {
+                continue;
+            }
+            if tile < -1 {
+                return Err(WfcError::InvalidArgument { reason: format!("tile must be -1 (free) or an index, got {}", tile) });
+            }
+            let tile_idx = tile as usize;
+            if tile_idx >= self.tiles.len() {
+                return Err(WfcError::TileOutOfRange { tile_idx, tile_count: self.tiles.len() });
+            }
+            if !self.matrix.get(idx).contains(tile_idx) || !self.active[idx] {
+                return Err(WfcError::Contradiction);
+            }
+            pins.push((idx, tile_idx));
+        }
+
+        self.untouched = false;
+        for &(idx, tile_idx) in &pins {
+            self.set_mask(idx, TileMask::single(self.tiles.len(), tile_idx));
+            self.frozen.insert(idx, tile_idx);
+            self.stack.push((idx / w, idx % w));
+        }
+        if self.propagate() {
+            Ok(())
+        } else {
+            Err(WfcError::Contradiction)
+        }
+    }
+
+    /// Returns the cell at `(row, col)` to superposition, dropping any
+    /// `set_cell` pin, and re-propagates from its neighbors so it only keeps
+    /// the tiles they still allow. Constraints the old value placed on other
+    /// cells are not lifted. Returns `false` if the position is invalid or
+    /// propagation hits a contradiction.
+    pub fn clear_cell(&mut self, row: usize, col: usize) -> bool {
+        self.resume_propagation(0);
+        if row >= self.output_height || col >= self.output_width {
+            return false;
         }
+
+        let idx = row * self.output_width + col;
+        self.frozen.remove(&idx);
+        self.set_mask(idx, self.initial_mask(idx));
         self.stack.clear();
-        self.local_reset_size = 8;
-        self.local_reset_attempts = 0;
+        for &(dr, dc) in self.directions {
+            if let Some(pos) = self.neighbor(row, col, dr, dc) {
+                self.stack.push(pos);
+            }
+        }
+        self.propagate()
     }
 
-    pub fn get_collapsed_count(&self) -> usize {
-        self.entropy_map.iter().filter(|&&e| e == 1).count()
+    /// Forbids `tile_idx` at `(row, col)` and propagates the change. Returns
+    /// `false` if the position or tile is invalid, if the ban would leave the
+    /// cell with no options (the cell is then left untouched), or if
+    /// propagation hits a contradiction.
+    pub fn ban_tile(&mut self, row: usize, col: usize, tile_idx: usize) -> bool {
+        self.resume_propagation(0);
+        if row >= self.output_height || col >= self.output_width || tile_idx >= self.tiles.len() {
+            return false;
+        }
+
+        let idx = row * self.output_width + col;
+        if !self.matrix.get(idx).contains(tile_idx) {
+            return true;
+        }
+
+        let mut updated_mask = self.matrix.get(idx).into_owned();
+        updated_mask.remove(tile_idx);
+        if updated_mask.is_empty() {
+            return false;
+        }
+
+        self.set_mask(idx, updated_mask);
+        self.stack.push((row, col));
+        self.propagate()
     }
 
-    pub fn get_image_data(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(self.output_size * self.output_size * 4);
-        for &mask in &self.matrix {
-            let color = self.get_display_color(mask);
-            data.push(color.r);
-            data.push(color.g);
-            data.push(color.b);
-            data.push(255);
+    /// `[row, col]` of the cell the next `step()` will collapse, or `None`
+    /// if it collapses nothing: once the grid is complete, or while a
+    /// propagation cut short by `set_propagation_budget` is pending, since
+    /// that step only continues it. Ties between equally constrained cells
+    /// are broken by a per-cell offset derived from the seed, so this is the
+    /// exact cell chosen as long as nothing changes the grid in between.
+    /// Scans every cell rather than peeking the heap, whose top may be stale.
+    pub fn next_cell(&self) -> Option<Box<[usize]>> {
+        if self.pending.is_some() {
+            return None;
         }
-        data
+        let start = if self.untouched && self.start_mode == StartMode::Center {
+            self.center_cell()
+        } else {
+            None
+        };
+        start
+            .or_else(|| self.lowest_among(0..self.matrix.len()))
+            .map(|i| vec![i / self.output_width, i % self.output_width].into_boxed_slice())
     }
 
-    fn get_display_color(&self, mask: u128) -> Color {
-        let mut r = 0u32;
-        let mut g = 0u32;
-        let mut b = 0u32;
-        let mut count = 0u32;
+    // The undecided active cell of `cells` the heap would pick first, found
+    // by a linear scan.
+    fn lowest_among(&self, cells: impl Iterator<Item = usize>) -> Option<usize> {
+        cells
+            .filter(|&i| self.entropy_map[i] > 1 && self.active[i])
+            .map(|i| (self.selection_key(i), i))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+            .map(|(_, i)| i)
+    }
 
-        for i in 0..self.tiles.len() {
-            if (mask & (1 << i)) != 0 {
-                let c = self.tiles[i][0][0];
-                r += c.r as u32;
-                g += c.g as u32;
-                b += c.b as u32;
-                count += 1;
-            }
+    /// Limits generation to the cells whose byte in `active` (one per cell,
+    /// row-major) is non-zero, e.g. a circle for a stencil-shaped output.
+    /// Inactive cells are never collapsed, neither constrain nor are
+    /// constrained by their neighbors, and render fully transparent. The
+    /// grid is cleared as by a full reset, keeping pinned cells and counters.
+    pub fn set_active_mask(&mut self, active: &[u8]) -> Result<(), WfcError> {
+        if active.len() != self.matrix.len() {
+            return Err(WfcError::BufferSizeMismatch { expected: self.matrix.len(), found: active.len() });
+        }
+        self.active = active.iter().map(|&a| a != 0).collect();
+        self.clear_grid();
+        Ok(())
+    }
+
+    /// Pins `tile_idx` across the whole bottom row and forbids it everywhere
+    /// else, then propagates, as with the "ground" pattern of classic
+    /// overlapping WFC. The constraint is kept through resets. Errors without
+    /// touching the grid if the tile is invalid or can't be placed; if
+    /// propagation contradicts, the grid is left as-is so the caller can
+    /// `reset()`.
+    pub fn set_ground(&mut self, tile_idx: usize) -> Result<(), WfcError> {
+        self.resume_propagation(0);
+        if tile_idx >= self.tiles.len() {
+            return Err(WfcError::TileOutOfRange { tile_idx, tile_count: self.tiles.len() });
+        }
+
+        let previous = self.ground.replace(tile_idx);
+        let masks: Vec<TileMask> = (0..self.matrix.len())
+            .map(|idx| &*self.matrix.get(idx) & &self.initial_mask(idx))
+            .collect();
+        if masks.iter().any(TileMask::is_empty) {
+            self.ground = previous;
+            return Err(WfcError::Contradiction);
         }
 
-        if count > 0 {
-            Color {
-                r: (r / count) as u8,
-                g: (g / count) as u8,
-                b: (b / count) as u8,
+        for (idx, mask) in masks.into_iter().enumerate() {
+            if mask != *self.matrix.get(idx) {
+                self.set_mask(idx, mask);
+                self.stack.push((idx / self.output_width, idx % self.output_width));
             }
+        }
+        if self.propagate() {
+            Ok(())
         } else {
-            Color { r: 255, g: 0, b: 255 }
+            Err(WfcError::Contradiction)
         }
     }
-}
-
-fn extract_tiles(input: &Vec<Vec<Color>>, tile_size: usize) -> (Vec<Tile>, Vec<f32>) {
-    let mut tile_counts: HashMap<Tile, usize> = HashMap::new();
-    let rows = input.len();
-    let cols = input[0].len();
 
-    for r in 0..=(rows - tile_size) {
-        for c in 0..=(cols - tile_size) {
-            let mut tile = Vec::with_capacity(tile_size);
-            for tr in 0..tile_size {
-                let mut row = Vec::with_capacity(tile_size);
-                for tc in 0..tile_size {
-                    row.push(input[r + tr][c + tc]);
-                }
-                tile.push(row);
-            }
-            
-            for _ in 0..4 {
-                *tile_counts.entry(tile.clone()).or_insert(0) += 1;
-                tile = rotate_tile(&tile);
+    /// Runs `step()` until the grid is fully collapsed, without crossing the
+    /// WASM boundary per step. Gives up after `MAX_STEPS_PER_CELL` steps per
+    /// cell so a pathological input can't hang the page; returns `false` in
+    /// that case, or if a contradiction callback halted the grid, so the
+    /// caller can `reset()` and retry.
+    pub fn run_to_completion(&mut self) -> bool {
+        let max_steps = self.matrix.len().saturating_mul(MAX_STEPS_PER_CELL);
+        for _ in 0..max_steps {
+            if !self.step() {
+                return !self.halted;
             }
         }
+        false
     }
 
-    let mut tiles = Vec::new();
-    let mut weights = Vec::new();
-    for (tile, count) in tile_counts {
-        tiles.push(tile);
-        weights.push(count as f32);
+    /// Steps until the grid is collapsed or `max_steps` have been taken.
+    /// Returns `1` on completion, `0` if the budget ran out with work
+    /// remaining or a contradiction callback halted the grid, and `-1` if contradictions forced more than
+    /// `MAX_FULL_RESETS` full restarts during the call, in which case the
+    /// input is unlikely to finish at all.
+    pub fn run_with_budget(&mut self, max_steps: usize) -> i32 {
+        let resets_before = self.stats.full_resets;
+        for _ in 0..max_steps {
+            if !self.step() {
+                return if self.halted { 0 } else { 1 };
+            }
+            if self.stats.full_resets - resets_before > MAX_FULL_RESETS {
+                return -1;
+            }
+        }
+        if self.is_complete() { 1 } else { 0 }
     }
 
-    (tiles, weights)
-}
-
-fn rotate_tile(tile: &Tile) -> Tile {
-    let size = tile.len();
-    let mut new_tile = vec![vec![Color { r: 0, g: 0, b: 0 }; size]; size];
-    for r in 0..size {
-        for c in 0..size {
-            new_tile[c][size - 1 - r] = tile[r][c];
+    /// Runs up to `max_attempts` solves, like `run_with_budget` with the
+    /// budget of `run_to_completion`, until one completes. Before each retry
+    /// the generator is reseeded with a value drawn from itself and the grid
+    /// is cleared, keeping pinned cells, so a retry doesn't replay the failed
+    /// choices while a seeded engine still retries deterministically.
+    /// `seed()` reports the seed of the last attempt.
+    pub fn solve_with_retries(&mut self, max_attempts: usize) -> bool {
+        let budget = self.matrix.len().saturating_mul(MAX_STEPS_PER_CELL);
+        for attempt in 0..max_attempts {
+            if attempt > 0 {
+                let seed = self.rng.next_u64();
+                self.rng = EngineRng::new(seed);
+                self.clear_grid();
+            }
+            if self.run_with_budget(budget) == 1 {
+                return true;
+            }
         }
+        false
     }
-    new_tile
-}
 
-fn compute_adjacencies(tiles: &Vec<Tile>) -> Vec<HashMap<(isize, isize), u128>> {
-    let mut adj = vec![HashMap::new(); tiles.len()];
-    for i in 0..tiles.len() {
-        for j in 0..tiles.len() {
-            for &(dr, dc) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
-                if can_overlap(&tiles[i], &tiles[j], dr, dc) {
-                    *adj[i].entry((dr, dc)).or_insert(0) |= 1 << j;
-                }
+    /// Solves only the rows `r0..r1` and columns `c0..c1` (end exclusive,
+    /// clamped to the grid), picking cells there as `step` would. Collapses
+    /// still propagate past the rectangle, so a later global solve builds
+    /// around the finished region. Returns `true` once every active cell in
+    /// it is collapsed, `false` if the step budget ran out first or a
+    /// contradiction callback halted the grid; full resets while resolving
+    /// contradictions clear the region too, and it is solved again from
+    /// scratch.
+    pub fn solve_region(&mut self, r0: usize, c0: usize, r1: usize, c1: usize) -> bool {
+        self.resume_propagation(0);
+        let (r1, c1) = (r1.min(self.output_height), c1.min(self.output_width));
+        let width = self.output_width;
+        let area = r1.saturating_sub(r0) * c1.saturating_sub(c0);
+        let mut budget = area.saturating_mul(MAX_STEPS_PER_CELL);
+        loop {
+            let cells = (r0..r1).flat_map(|r| (c0..c1).map(move |c| r * width + c));
+            let Some(idx) = self.lowest_among(cells) else {
+                return true;
+            };
+            if budget == 0 || self.halted {
+                return false;
             }
+            budget -= 1;
+            self.stats.steps += 1;
+            let tile = self.observe(idx);
+            self.collapse(idx, tile, 0);
         }
     }
-    adj
+
+    /// Tunes how contradictions escalate: the first local reset clears an
+    /// `initial` square, which widens by `growth` after every
+    /// `attempts_before_growth` contradictions until it covers the grid and a
+    /// full reset is made instead. Defaults are `8`, `4` and `8`; small
+    /// outputs want a smaller `initial`. `growth` is raised to at least `1`.
+    pub fn set_reset_params(&mut self, initial: usize, growth: usize, attempts_before_growth: usize) {
+        self.reset_initial_size = initial;
+        self.reset_growth = growth.max(1);
+        self.reset_attempts_before_growth = attempts_before_growth;
+        self.local_reset_size = initial;
+        self.local_reset_attempts = 0;
+    }
+
+    /// Limits how many past decisions are remembered for backtracking. Each
+    /// kept decision costs memory proportional to the cells it changed;
+    /// `0` disables backtracking.
+    pub fn max_backtrack_depth(&mut self, depth: usize) {
+        self.max_backtrack_depth = depth;
+        self.trim_history();
+    }
+
+    /// Chooses how later contradictions are recovered from; see
+    /// `ContradictionStrategy`.
+    pub fn set_strategy(&mut self, strategy: ContradictionStrategy) {
+        self.strategy = strategy;
+    }
+
+    fn push_decision(&mut self, idx: usize, tile: usize) {
+        if self.max_backtrack_depth == 0 {
+            return;
+        }
+        self.decisions.push_back(Decision { idx, tile, trail_start: self.trail.len() });
+        self.trim_history();
+    }
+
+    fn trim_history(&mut self) {
+        while self.decisions.len() > self.max_backtrack_depth {
+            self.decisions.pop_front();
+            // Changes made before the new oldest decision can never be undone.
+            let cut = self.decisions.front().map_or(self.trail.len(), |d| d.trail_start);
+            self.trail.drain(..cut);
+            for d in self.decisions.iter_mut() {
+                d.trail_start -= cut;
+            }
+        }
+    }
+
+    fn clear_history(&mut self) {
+        self.decisions.clear();
+        self.trail.clear();
+    }
+
+    /// Undoes decisions newest-first, banning the tile chosen at each, until
+    /// the ban propagates cleanly. Returns `false` once the history is used up.
+    fn backtrack(&mut self) -> bool {
+        while let Some(decision) = self.decisions.pop_back() {
+            self.stats.backtracks += 1;
+            self.record(HistoryEvent::Undo {
+                row: decision.idx / self.output_width,
+                col: decision.idx % self.output_width,
+                tile: decision.tile,
+            });
+            while self.trail.len() > decision.trail_start {
+                let (idx, mask) = self.trail.pop().unwrap();
+                self.write_mask(idx, mask);
+            }
+            self.stack.clear();
+
+            let mut remaining = self.matrix.get(decision.idx).into_owned();
+            remaining.remove(decision.tile);
+            if remaining.is_empty() {
+                continue;
+            }
+            self.set_mask(decision.idx, remaining);
+            self.stack.push((decision.idx / self.output_width, decision.idx % self.output_width));
+            if self.propagate() {
+                return true;
+            }
+        }
+        self.clear_history();
+        false
+    }
+
+    fn handle_contradiction(&mut self, row: usize, col: usize) {
+        self.stats.contradictions += 1;
+        self.last_contradiction_pos = Some((row, col));
+        if !self.allow_recovery(row, col) {
+            self.stack.clear();
+            self.halted = true;
+            return;
+        }
+        if self.strategy != ContradictionStrategy::LocalReset {
+            self.stats.full_resets += 1;
+            self.clear_grid();
+            return;
+        }
+        self.local_reset_attempts += 1;
+        
+        if self.local_reset_attempts > self.reset_attempts_before_growth {
+            self.local_reset_attempts = 0;
+            self.local_reset_size += self.reset_growth;
+        }
+
+        loop {
+            // If area too big, just reset everything
+            if self.local_reset_size > self.output_width.max(self.output_height) {
+                self.stats.full_resets += 1;
+                self.clear_grid();
+                return;
+            }
+            self.stats.local_resets += 1;
+            if self.reset_local(row, col, self.local_reset_size) {
+                return;
+            }
+            // The surroundings already contradict inside this square; widen it.
+            self.local_reset_size += self.reset_growth;
+        }
+    }
+
+    /// Clears a `size` square around `(row, col)` and re-propagates from the
+    /// ring of cells just outside it, so the cleared area starts out
+    /// consistent with its surroundings. Returns `false` if that propagation
+    /// contradicts.
+    fn reset_local(&mut self, row: usize, col: usize, size: usize) -> bool {
+        self.record(HistoryEvent::LocalReset { row, col, size });
+        self.clear_history();
+        let half = (size / 2) as isize;
+
+        for dr in -half..half {
+            for dc in -half..half {
+                if let Some((nr, nc)) = self.neighbor(row, col, dr, dc) {
+                    let idx = nr * self.output_width + nc;
+                    self.set_mask(idx, self.initial_mask(idx));
+                }
+            }
+        }
+        self.stack.clear();
+
+        // Cells inside the square with edge, ground or pin constraints carry
+        // them inward.
+        for dr in -half..half {
+            for dc in -half..half {
+                if let Some((nr, nc)) = self.neighbor(row, col, dr, dc) {
+                    if self.entropy_map[nr * self.output_width + nc] < self.tiles.len() {
+                        self.stack.push((nr, nc));
+                    }
+                }
+            }
+        }
+
+        for dr in -half - 1..=half {
+            for dc in -half - 1..=half {
+                let on_ring = dr == -half - 1 || dr == half || dc == -half - 1 || dc == half;
+                if on_ring {
+                    if let Some(pos) = self.neighbor(row, col, dr, dc) {
+                        self.stack.push(pos);
+                    }
+                }
+            }
+        }
+        self.propagate()
+    }
+
+    /// Mask a cell starts from after a reset: every tile, narrowed in
+    /// `EdgeMode::Constrained` to those allowed on each border it lies on,
+    /// and by the ground tile if one is set. Cells pinned by `set_cell` keep
+    /// their tile.
+    fn initial_mask(&self, idx: usize) -> TileMask {
+        if !self.active[idx] {
+            return self.all_flags.clone();
+        }
+        if let Some(&tile) = self.frozen.get(&idx) {
+            return TileMask::single(self.tiles.len(), tile);
+        }
+        let mut mask = self.all_flags.clone();
+        let row = idx / self.output_width;
+        let col = idx % self.output_width;
+        if let Some(ground) = self.ground {
+            if row + 1 == self.output_height {
+                mask = TileMask::single(self.tiles.len(), ground);
+            } else {
+                mask.remove(ground);
+            }
+        }
+        if self.edge_mode != EdgeMode::Constrained || self.wrap {
+            return mask;
+        }
+
+        let sides = [row == 0, row + 1 == self.output_height, col == 0, col + 1 == self.output_width];
+        for (side, &on_side) in sides.iter().enumerate() {
+            if on_side {
+                mask &= &self.border_masks[side];
+            }
+        }
+        mask
+    }
+
+    /// Narrows cells to their initial masks and propagates the result. A
+    /// no-op without edge, ground or pinned-cell constraints; returns `false`
+    /// if the constraints themselves contradict.
+    fn apply_initial_constraints(&mut self) -> bool {
+        let edges = self.edge_mode == EdgeMode::Constrained && !self.wrap;
+        if !edges && self.ground.is_none() && self.frozen.is_empty() {
+            return true;
+        }
+
+        for idx in 0..self.matrix.len() {
+            let mask = self.initial_mask(idx);
+            if mask != *self.matrix.get(idx) {
+                self.set_mask(idx, mask);
+                self.stack.push((idx / self.output_width, idx % self.output_width));
+            }
+        }
+        self.propagate()
+    }
+
+    /// Cell at offset `(dr, dc)` from `(row, col)`, wrapping around the edges
+    /// in wrap mode and `None` if it falls outside the grid otherwise.
+    fn neighbor(&self, row: usize, col: usize, dr: isize, dc: isize) -> Option<(usize, usize)> {
+        let nr = row as isize + dr;
+        let nc = col as isize + dc;
+        let (h, w) = (self.output_height as isize, self.output_width as isize);
+
+        if self.wrap {
+            Some((nr.rem_euclid(h) as usize, nc.rem_euclid(w) as usize))
+        } else if nr >= 0 && nr < h && nc >= 0 && nc < w {
+            Some((nr as usize, nc as usize))
+        } else {
+            None
+        }
+    }
+
+    // The middle cell for `StartMode::Center`, if it is still undecided.
+    fn center_cell(&self) -> Option<usize> {
+        let idx = (self.output_height / 2) * self.output_width + self.output_width / 2;
+        (self.entropy_map.get(idx).is_some_and(|&e| e > 1) && self.active[idx]).then_some(idx)
+    }
+
+    fn find_lowest_entropy(&mut self) -> Option<usize> {
+        while let Some(entry) = self.heap.pop() {
+            if self.entropy_map[entry.idx] > 1 && self.versions[entry.idx] == entry.version && self.active[entry.idx] {
+                return Some(entry.idx);
+            }
+        }
+        None
+    }
+
+    fn push_candidate(&mut self, idx: usize) {
+        self.heap.push(HeapEntry {
+            entropy: self.selection_key(idx),
+            idx,
+            version: self.versions[idx],
+        });
+    }
+
+    // Heap key of a cell in its current state.
+    fn selection_key(&self, idx: usize) -> f64 {
+        let base = if self.raw_entropy {
+            self.entropy_map[idx] as f64
+        } else {
+            self.shannon_map[idx]
+        };
+        // A tiny pseudo-random offset breaks ties between equally constrained
+        // cells. It's derived from the cell's version rather than drawn from
+        // the RNG so that `load_state` can rebuild an identical heap.
+        let noise = hash_unit(self.rng.seed(), idx as u64, self.versions[idx] as u64);
+        // With a selection bias, each neighbor not yet collapsed adds more
+        // than the noise can, while still staying below any real entropy
+        // difference.
+        let open_neighbors = if self.selection_bias {
+            (self.directions.len() - self.collapsed_neighbors(idx)) as f64
+        } else {
+            0.0
+        };
+        base + (noise + open_neighbors) * 1e-6
+    }
+
+    fn collapsed_neighbors(&self, idx: usize) -> usize {
+        let (row, col) = (idx / self.output_width, idx % self.output_width);
+        self.directions
+            .iter()
+            .filter_map(|&(dr, dc)| self.neighbor(row, col, dr, dc))
+            .filter(|&(nr, nc)| self.entropy_map[nr * self.output_width + nc] == 1)
+            .count()
+    }
+
+    // Re-keys the uncollapsed neighbors of a cell that just collapsed or
+    // reopened, since their selection bias depends on it.
+    fn refresh_neighbors(&mut self, idx: usize) {
+        let (row, col) = (idx / self.output_width, idx % self.output_width);
+        for dir in 0..self.directions.len() {
+            let (dr, dc) = self.directions[dir];
+            if let Some((nr, nc)) = self.neighbor(row, col, dr, dc) {
+                let n_idx = nr * self.output_width + nc;
+                if self.entropy_map[n_idx] > 1 {
+                    self.versions[n_idx] = self.versions[n_idx].wrapping_add(1);
+                    self.push_candidate(n_idx);
+                }
+            }
+        }
+    }
+
+    fn rebuild_heap(&mut self) {
+        self.heap.clear();
+        for i in 0..self.matrix.len() {
+            if self.entropy_map[i] > 1 {
+                self.push_candidate(i);
+            }
+        }
+    }
+
+    fn set_mask(&mut self, idx: usize, mask: TileMask) {
+        if !self.decisions.is_empty() {
+            self.trail.push((idx, self.matrix.get(idx).into_owned()));
+        }
+        self.write_mask(idx, mask);
+    }
+
+    fn write_mask(&mut self, idx: usize, mask: TileMask) {
+        let was_collapsed = self.entropy_map[idx] == 1;
+        self.entropy_map[idx] = mask.count_ones() as usize;
+        self.shannon_map[idx] = self.shannon_entropy(&mask);
+        self.matrix.set(idx, mask);
+        if self.entropy_map[idx] != 1 {
+            self.finalized[idx] = false;
+        }
+        self.display_colors.get_mut()[idx] = None;
+        self.mark_dirty(idx);
+        self.versions[idx] = self.versions[idx].wrapping_add(1);
+        if self.entropy_map[idx] > 1 {
+            self.push_candidate(idx);
+        }
+        if self.selection_bias && was_collapsed != (self.entropy_map[idx] == 1) {
+            self.refresh_neighbors(idx);
+        }
+    }
+
+    fn mark_dirty(&mut self, idx: usize) {
+        if !self.dirty_flags[idx] {
+            self.dirty_flags[idx] = true;
+            self.dirty.push(idx);
+        }
+    }
+
+    fn mark_all_dirty(&mut self) {
+        for idx in 0..self.matrix.len() {
+            self.mark_dirty(idx);
+        }
+    }
+
+    fn shannon_entropy(&self, mask: &TileMask) -> f64 {
+        let mut sum_weights = 0.0;
+        let mut sum_weight_logs = 0.0;
+        for i in mask.iter() {
+            sum_weights += self.weights[i] as f64;
+            sum_weight_logs += self.weight_log_weights[i];
+        }
+
+        if sum_weights <= 0.0 {
+            return 0.0;
+        }
+        sum_weights.ln() - sum_weight_logs / sum_weights
+    }
+
+    /// Recomputes the `w * ln(w)` terms and every cell's Shannon entropy after
+    /// `weights` change, then re-keys the heap.
+    fn update_weight_cache(&mut self) {
+        self.weight_log_weights = self.weights
+            .iter()
+            .map(|&w| if w > 0.0 { w as f64 * (w as f64).ln() } else { 0.0 })
+            .collect();
+        for i in 0..self.matrix.len() {
+            self.shannon_map[i] = self.shannon_entropy(&self.matrix.get(i));
+        }
+        // Blends are weighted, so every cached color is stale.
+        self.display_colors.get_mut().fill(None);
+        self.mark_all_dirty();
+        self.rebuild_heap();
+    }
+
+    fn observe(&mut self, idx: usize) -> usize {
+        let mut options = Vec::new();
+        let mut weights = Vec::new();
+        let mut total_weight = 0.0;
+        for i in self.matrix.get(idx).iter() {
+            let w = self.weights[i] * self.bias_factor(idx, i) * self.preference(idx, i);
+            options.push(i);
+            weights.push(w);
+            total_weight += w;
+        }
+
+        if options.is_empty() {
+            // This shouldn't happen if propagate works right, but safety first
+            return 0;
+        }
+
+        if !total_weight.is_finite() || total_weight <= 0.0 {
+            // Only zero-weight tiles are left, or the sum overflowed; fall
+            // back to a uniform pick rather than an empty or infinite range.
+            return options[self.rng.gen_range(0..options.len())];
+        }
+
+        let mut r = self.rng.gen_range(0.0..total_weight);
+        for (&idx, &w) in options.iter().zip(&weights) {
+            r -= w;
+            if r <= 0.0 {
+                return idx;
+            }
+        }
+        options[options.len() - 1]
+    }
+
+    // Multiplier `set_preference` gave `tile` at cell `idx`, `1` if none.
+    fn preference(&self, idx: usize, tile: usize) -> f32 {
+        self.preferences
+            .get(&idx)
+            .and_then(|prefs| prefs.iter().find(|&&(t, _)| t == tile))
+            .map_or(1.0, |&(_, strength)| strength)
+    }
+
+    // Product of `directional_bias[d]` over the directions `d` in which some
+    // collapsed neighbor of `idx` was seen with `tile` in the samples.
+    fn bias_factor(&self, idx: usize, tile: usize) -> f32 {
+        if self.directional_bias.is_empty() {
+            return 1.0;
+        }
+        let (row, col) = (idx / self.output_width, idx % self.output_width);
+        let mut factor = 1.0;
+        for (dir, &(dr, dc)) in self.directions.iter().enumerate() {
+            // The neighbor from which `idx` lies in direction `dir`.
+            let Some((nr, nc)) = self.neighbor(row, col, -dr, -dc) else {
+                continue;
+            };
+            let n_idx = nr * self.output_width + nc;
+            if !self.active[n_idx] {
+                continue;
+            }
+            if let Some(t) = self.matrix.get(n_idx).single_tile() {
+                if self.seen[t][dir].binary_search(&tile).is_ok() {
+                    factor *= self.directional_bias[dir];
+                }
+            }
+        }
+        factor
+    }
+
+    fn propagate(&mut self) -> bool {
+        self.propagate_within(0) == Some(true)
+    }
+
+    // `propagate`, stopping with `None` once `budget` cells have been
+    // visited and the stack is still not empty; `0` means no limit.
+    fn propagate_within(&mut self, budget: usize) -> Option<bool> {
+        let mut visited = 0;
+        while let Some((r, c)) = self.stack.pop() {
+            if budget > 0 && visited == budget {
+                self.stack.push((r, c));
+                return None;
+            }
+            visited += 1;
+            if !self.active[r * self.output_width + c] {
+                continue;
+            }
+            let current_mask = self.matrix.get(r * self.output_width + c).into_owned();
+
+            for (dir, &(dr, dc)) in self.directions.iter().enumerate() {
+                if let Some((nr, nc)) = self.neighbor(r, c, dr, dc) {
+                    let n_idx = nr * self.output_width + nc;
+
+                    if self.entropy_map[n_idx] <= 1 || !self.active[n_idx] {
+                        continue;
+                    }
+
+                    let mut allowed_mask = TileMask::empty(self.tiles.len());
+                    for i in current_mask.iter() {
+                        allowed_mask |= &self.adjacencies[i][dir];
+                    }
+
+                    let updated_mask = &*self.matrix.get(n_idx) & &allowed_mask;
+                    if updated_mask.is_empty() {
+                        return Some(false);
+                    }
+
+                    if updated_mask != *self.matrix.get(n_idx) {
+                        self.stats.cells_narrowed += 1;
+                        self.set_mask(n_idx, updated_mask);
+                        self.stack.push((nr, nc));
+                    }
+                }
+            }
+        }
+        Some(true)
+    }
+
+    /// Clears the grid back to full superposition, apart from edge and ground
+    /// constraints, and drops any cells pinned with `set_cell`. The RNG is
+    /// not reseeded, so a seeded engine keeps drawing from the same
+    /// deterministic sequence.
+    pub fn reset(&mut self) {
+        self.frozen.clear();
+        self.clear_grid();
+        self.stats = SolveStats::default();
+        self.last_contradiction_pos = None;
+        self.last_collapse = None;
+    }
+
+    /// Like `reset()`, but also reseeds the RNG with `seed` and forgets the
+    /// grid's past, so every call with the same seed replays the same solve
+    /// however the engine was used before. Gives full control over the seed
+    /// of each attempt where `solve_with_retries` draws them itself.
+    pub fn reset_with_seed(&mut self, seed: u64) {
+        self.rng = EngineRng::new(seed);
+        self.reset();
+        // Heap tie-breaks hash the cell versions, which carry the history.
+        self.versions.fill(0);
+        self.rebuild_heap();
+    }
+
+    // Full restart of the grid; unlike `reset()`, keeps pinned cells and the
+    // stats so restarts forced by contradictions are tallied.
+    fn clear_grid(&mut self) {
+        self.record(HistoryEvent::Restart);
+        self.clear_history();
+        self.heap.clear();
+        for i in 0..self.matrix.len() {
+            self.set_mask(i, self.all_flags.clone());
+        }
+        self.stack.clear();
+        self.pending = None;
+        self.halted = false;
+        self.apply_initial_constraints();
+        self.local_reset_size = self.reset_initial_size;
+        self.local_reset_attempts = 0;
+        self.untouched = true;
+    }
+
+    /// Serializes the grid, `set_cell` pins, `set_active_mask` stencil,
+    /// `set_ground` tile and RNG position so a partial run can be resumed
+    /// later with `load_state`, e.g. across page reloads via `localStorage`.
+    /// Any propagation left over by `set_propagation_budget` is finished
+    /// first, so the snapshot is consistent. Backtracking history is not
+    /// included.
+    pub fn save_state(&mut self) -> Vec<u8> {
+        self.resume_propagation(0);
+        let state = SavedState {
+            output_width: self.output_width,
+            output_height: self.output_height,
+            tile_count: self.tiles.len(),
+            matrix: self.matrix.iter().map(Cow::into_owned).collect(),
+            versions: self.versions.clone(),
+            rng_seed: self.rng.seed(),
+            rng_draws: self.rng.draws(),
+            rng_words: self.rng.state(),
+            frozen: {
+                let mut pins: Vec<(usize, usize)> = self.frozen.iter().map(|(&idx, &tile)| (idx, tile)).collect();
+                pins.sort_unstable();
+                pins
+            },
+            active: self.active.clone(),
+            ground: self.ground,
+            local_reset_size: self.local_reset_size,
+            local_reset_attempts: self.local_reset_attempts,
+        };
+        bincode::serialize(&state).expect("serializing to a Vec cannot fail")
+    }
+
+    /// Restores a snapshot from `save_state`. The engine must have been built
+    /// from the same input and dimensions; otherwise an error is returned and
+    /// the engine is left unchanged.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), WfcError> {
+        let state: SavedState =
+            bincode::deserialize(bytes).map_err(|e| WfcError::InvalidState { reason: e.to_string() })?;
+
+        if state.output_width != self.output_width || state.output_height != self.output_height {
+            return Err(WfcError::StateMismatch);
+        }
+        if state.tile_count != self.tiles.len()
+            || state.matrix.len() != self.matrix.len()
+            || state.versions.len() != self.matrix.len()
+            || state.active.len() != self.matrix.len()
+        {
+            return Err(WfcError::StateMismatch);
+        }
+        // Checked up front so a corrupted save can't index past a mask's
+        // words or leave an empty cell behind, and fails without touching self.
+        if let Some(i) = state.matrix.iter().position(|m| !m.fits(self.tiles.len()) || m.is_empty()) {
+            return Err(WfcError::InvalidState { reason: format!("cell {} has a malformed or empty tile mask", i) });
+        }
+        if let Some(&(idx, tile)) = state.frozen.iter().find(|&&(idx, tile)| idx >= self.matrix.len() || tile >= self.tiles.len()) {
+            return Err(WfcError::InvalidState { reason: format!("pin of tile {} at cell {} is out of range", tile, idx) });
+        }
+        if let Some(tile) = state.ground.filter(|&tile| tile >= self.tiles.len()) {
+            return Err(WfcError::InvalidState { reason: format!("ground tile {} is out of range", tile) });
+        }
+        let rng = EngineRng::resume(state.rng_seed, state.rng_draws, state.rng_words)
+            .ok_or_else(|| WfcError::InvalidState { reason: "random generator state is all zero".to_string() })?;
+
+        self.clear_history();
+        self.stack.clear();
+        self.pending = None;
+        self.halted = false;
+        self.untouched = false;
+        self.matrix = MaskGrid::from_masks(self.tiles.len(), state.matrix);
+        self.versions = state.versions;
+        for i in 0..self.matrix.len() {
+            self.entropy_map[i] = self.matrix.get(i).count_ones() as usize;
+            // Whatever the saved run decided is final now.
+            self.finalized[i] = self.entropy_map[i] == 1;
+        }
+        self.rng = rng;
+        self.frozen = state.frozen.into_iter().collect();
+        self.active = state.active;
+        self.ground = state.ground;
+        self.update_weight_cache();
+        self.local_reset_size = state.local_reset_size;
+        self.local_reset_attempts = state.local_reset_attempts;
+        Ok(())
+    }
+
+    /// Seed of the random generator. Engines built without `seed` draw one
+    /// at random, so this is what reproduces their run. Changed by
+    /// `solve_with_retries`.
+    pub fn seed(&self) -> u64 {
+        self.rng.seed()
+    }
+
+    /// Position of the random generator, as the number of values drawn
+    /// since it was seeded. The generator is never reseeded from entropy
+    /// after construction, so the seed plus this count pin down every
+    /// future choice; `save_state` stores the generator's state directly.
+    pub fn rng_state(&self) -> u64 {
+        self.rng.draws()
+    }
+
+    /// Moves the random generator to `state`, a value from `rng_state` of an
+    /// engine with the same seed. The position is reached by replaying the
+    /// draws, from the current position when `state` lies ahead of it and
+    /// from the seed otherwise. A replay longer than one draw per cell, tile
+    /// and step of the `run_to_completion` budget is more than any solve of
+    /// this grid could need, so it is rejected instead of hanging.
+    pub fn set_rng_state(&mut self, state: u64) -> Result<(), WfcError> {
+        let reachable = (self.matrix.len() * self.tiles.len()).saturating_mul(MAX_STEPS_PER_CELL) as u64;
+        let draws = self.rng.draws();
+        let replay = if state >= draws { state - draws } else { state };
+        if replay > reachable {
+            return Err(WfcError::InvalidArgument { reason: format!("random generator position {} is out of reach", state) });
+        }
+        let seed = self.rng.seed();
+        self.rng.restore(seed, state);
+        Ok(())
+    }
+
+    /// Enables or disables toroidal wrapping. Takes effect on the next
+    /// propagation, so set it before stepping. Constrained edges are only
+    /// re-applied by `reset()`.
+    pub fn set_wrap(&mut self, wrap: bool) {
+        self.resume_propagation(0);
+        self.wrap = wrap;
+    }
+
+    /// Switches cell selection between weighted Shannon entropy (default) and
+    /// the raw count of remaining candidates.
+    pub fn set_raw_entropy(&mut self, raw: bool) {
+        self.raw_entropy = raw;
+        self.rebuild_heap();
+    }
+
+    /// Biases `step` towards continuing the example in chosen directions, e.g.
+    /// for water that should keep flowing down. `bias` holds one multiplier
+    /// per neighbor direction, indexed like the adjacency table (up, down,
+    /// left, right, then any diagonal or hex extras). When a cell's neighbor
+    /// has collapsed to tile `t`, every candidate seen in direction `d` of `t`
+    /// in the samples has its weight multiplied by `bias[d]`, so `[1, 4, 1, 1]`
+    /// favors what appeared below. Only the samples as drawn count, not their
+    /// transformed variants; tiles from `from_tiles_and_rules` use their
+    /// rules instead. Entropy, and so cell order, is unaffected. An empty
+    /// `bias`, the default, turns it off.
+    pub fn set_directional_bias(&mut self, bias: Vec<f32>) -> Result<(), WfcError> {
+        if !bias.is_empty() && bias.len() != self.directions.len() {
+            return Err(WfcError::InvalidArgument {
+                reason: format!("{} bias values given for {} directions", bias.len(), self.directions.len()),
+            });
+        }
+        if let Some(i) = bias.iter().position(|b| !b.is_finite() || *b < 0.0) {
+            return Err(WfcError::InvalidArgument { reason: format!("bias {} is negative or not finite", i) });
+        }
+        self.directional_bias = bias;
+        Ok(())
+    }
+
+    /// Turns the collapsed-neighbor preference of
+    /// `EngineOptions::selection_bias` on or off.
+    pub fn set_selection_bias(&mut self, bias: bool) {
+        self.selection_bias = bias;
+        self.rebuild_heap();
+    }
+
+    /// `[row, col]` of the collapse behind the most recent contradiction
+    /// counted by `contradiction_count`, or `None` if there has been none
+    /// since the last `reset()`.
+    pub fn last_contradiction(&self) -> Option<Box<[usize]>> {
+        self.last_contradiction_pos.map(|(row, col)| vec![row, col].into_boxed_slice())
+    }
+
+    /// Cell and tile chosen by the most recent `step`, or `None` if it hasn't
+    /// been called since the last `reset()`. The choice may since have been
+    /// undone by backtracking or a reset resolving a contradiction.
+    pub fn last_collapse(&self) -> Option<CollapseInfo> {
+        self.last_collapse
+    }
+
+    /// Whether every cell has collapsed to a single tile. Stops at the first
+    /// cell still in superposition.
+    pub fn is_complete(&self) -> bool {
+        self.entropy_map.iter().zip(&self.active).all(|(&e, &active)| e == 1 || !active)
+    }
+
+    /// Number of contradictions that needed a local or full reset since the
+    /// last `reset()`. A rough measure of how hard the input is to solve.
+    pub fn contradiction_count(&self) -> usize {
+        self.stats.contradictions
+    }
+
+    /// Solver counters since the last `reset()`, for comparing how hard
+    /// different inputs and parameters are to solve.
+    pub fn get_stats(&self) -> SolveStats {
+        self.stats
+    }
+
+    /// Number of active cells down to a single tile. That includes cells
+    /// forced by propagation after a pin or step: they are final as soon as
+    /// their mask narrows, and `step` never visits them. `collapse_forced`
+    /// reports them as collapses.
+    pub fn get_collapsed_count(&self) -> usize {
+        self.entropy_map.iter().zip(&self.active).filter(|&(&e, &active)| e == 1 && active).count()
+    }
+
+    /// Number of unique patterns extracted from the input.
+    pub fn tile_count(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Side length of a square output grid in cells; for a rectangular one,
+    /// its width. See `output_width` and `output_height`.
+    pub fn output_size(&self) -> usize {
+        self.output_width
+    }
+
+    /// Width of the output grid in cells.
+    pub fn output_width(&self) -> usize {
+        self.output_width
+    }
+
+    /// Height of the output grid in cells.
+    pub fn output_height(&self) -> usize {
+        self.output_height
+    }
+
+    /// Side length of a square pattern in pixels; for a rectangular one, its
+    /// width. See `tile_width` and `tile_height`.
+    pub fn tile_size(&self) -> usize {
+        self.tile_width
+    }
+
+    /// Width of a pattern in pixels.
+    pub fn tile_width(&self) -> usize {
+        self.tile_width
+    }
+
+    /// Height of a pattern in pixels.
+    pub fn tile_height(&self) -> usize {
+        self.tile_height
+    }
+
+    /// Weight of each pattern, indexed by tile.
+    pub fn pattern_weights(&self) -> Vec<f32> {
+        self.weights.clone()
+    }
+
+    /// Adjacency table as nested arrays: `result[tile][dir]` lists the tiles
+    /// allowed at offset `dir` from `tile`, with directions ordered up, down,
+    /// left, right, then the diagonals or hex neighbors if enabled. An empty
+    /// list means the tile can never have a neighbor on that side.
+    pub fn export_adjacencies(&self) -> JsValue {
+        let table: Vec<Vec<Vec<u32>>> = self.adjacencies
+            .iter()
+            .map(|dirs| dirs.iter().map(|mask| mask.iter().map(|t| t as u32).collect()).collect())
+            .collect();
+        serde_wasm_bindgen::to_value(&table).expect("nested arrays always convert")
+    }
+
+    /// Whether any pattern has no compatible neighbor in some direction; see
+    /// `dead_tiles`.
+    pub fn has_dead_tiles(&self) -> bool {
+        self.adjacencies.iter().any(|dirs| dirs.iter().any(TileMask::is_empty))
+    }
+
+    /// Patterns with no compatible neighbor in at least one direction. Such
+    /// a tile contradicts as soon as it is chosen away from the border, so a
+    /// non-empty list suggests trying another `tile_size` or symmetry.
+    pub fn dead_tiles(&self) -> Vec<u32> {
+        (0..self.tiles.len())
+            .filter(|&i| self.adjacencies[i].iter().any(TileMask::is_empty))
+            .map(|i| i as u32)
+            .collect()
+    }
+
+    /// Patterns placed in no collapsed cell, in ascending order. Meant for
+    /// after a completed solve: tiles the solver keeps rejecting are
+    /// candidates for pruning from the source image. Cells still in
+    /// superposition count for nothing.
+    pub fn unused_tiles(&self) -> Vec<u32> {
+        let mut used = vec![false; self.tiles.len()];
+        for tile in self.matrix.iter().filter_map(|mask| mask.single_tile()) {
+            used[tile] = true;
+        }
+        (0..self.tiles.len()).filter(|&i| !used[i]).map(|i| i as u32).collect()
+    }
+
+    /// Checks that the adjacency table is symmetric: whenever tile B may sit
+    /// on some side of tile A, A may sit on the opposite side of B.
+    pub fn check_consistency(&self) -> bool {
+        adjacencies_symmetric(self.directions, &self.adjacencies)
+    }
+
+    /// Average fraction of the tileset allowed next to a tile, over every
+    /// tile and direction. Near `1` almost anything fits anywhere; a low
+    /// value means tight rules, which tend to cause more contradictions and
+    /// longer solves.
+    pub fn adjacency_density(&self) -> f32 {
+        let slots = self.tiles.len() * self.directions.len();
+        let allowed: u64 = self.adjacencies.iter().flatten().map(|m| m.count_ones() as u64).sum();
+        allowed as f32 / (slots * self.tiles.len()) as f32
+    }
+
+    /// Approximate memory held by the engine's per-cell state, tiles and
+    /// adjacency rules, in bytes. Per-cell state dominates on large outputs
+    /// and grows linearly with the cell count, so the figure for a small
+    /// output extrapolates to larger ones with the same tileset.
+    pub fn estimated_bytes(&self) -> usize {
+        use std::mem::size_of;
+        let mask_bytes = |m: &TileMask| size_of::<TileMask>() + m.heap_bytes();
+        let cells = self.matrix.len();
+
+        let grid = self.matrix.bytes()
+            + cells * (size_of::<usize>() + size_of::<u32>() + size_of::<f64>() + size_of::<Option<Color>>() + 2 * size_of::<bool>())
+            + self.dirty.capacity() * size_of::<usize>()
+            + self.heap.capacity() * size_of::<HeapEntry>()
+            + self.stack.capacity() * size_of::<(usize, usize)>()
+            + self.trail.capacity() * size_of::<(usize, TileMask)>();
+        let tiles = self
+            .tiles
+            .iter()
+            .map(|t| size_of::<Tile>() + t.iter().map(|row| size_of::<Vec<Color>>() + row.len() * size_of::<Color>()).sum::<usize>())
+            .sum::<usize>()
+            + self.weights.len() * (2 * size_of::<f32>() + size_of::<f64>())
+            + self.tile_colors.len() * size_of::<Color>();
+        let adjacencies = self
+            .adjacencies
+            .iter()
+            .map(|dirs| size_of::<Vec<TileMask>>() + dirs.iter().map(mask_bytes).sum::<usize>())
+            .sum::<usize>()
+            + self
+                .seen
+                .iter()
+                .map(|dirs| size_of::<Vec<Vec<usize>>>() + dirs.iter().map(|s| size_of::<Vec<usize>>() + s.capacity() * size_of::<usize>()).sum::<usize>())
+                .sum::<usize>();
+        grid + tiles + adjacencies
+    }
+
+    /// Representative color of each pattern, indexed by tile, as chosen by
+    /// `set_color_sampling`; the same one used for collapsed cells in
+    /// `get_image_data`.
+    pub fn get_palette(&self) -> Vec<Color> {
+        self.tile_colors.clone()
+    }
+
+    /// Picks which pixel of each pattern `get_image_data` and superposition
+    /// blends use. `TopLeft`, the default, is the faithful choice for the
+    /// overlapping model; `Center` or `Average` can read better as a preview
+    /// for larger tiles. `get_full_image_data` is unaffected.
+    pub fn set_color_sampling(&mut self, sampling: ColorSampling) {
+        self.color_sampling = sampling;
+        self.tile_colors = sample_colors(&self.tiles, sampling);
+        self.display_colors.get_mut().fill(None);
+        self.mark_all_dirty();
+    }
+
+    /// Chooses how `get_image_data` shows cells in superposition: a weighted
+    /// blend (`Average`, the default) or the heaviest remaining tile
+    /// (`MostLikely`). Collapsed cells look the same either way.
+    pub fn set_display_mode(&mut self, mode: DisplayMode) {
+        self.display_mode = mode;
+        self.display_colors.get_mut().fill(None);
+        self.mark_all_dirty();
+    }
+
+    /// RGBA bytes of pattern `tile_idx`, row by row, `tile_width * tile_height
+    /// * 4` long. Empty if the index is out of range.
+    pub fn get_tile_pixels(&self, tile_idx: usize) -> Vec<u8> {
+        self.tiles.get(tile_idx).map_or_else(Vec::new, tile_key)
+    }
+
+    /// Overrides the weight of `tile_idx`, e.g. to make rare patterns more
+    /// common. Takes effect from the next step. Returns `false` and changes
+    /// nothing if the index is out of range or the weight is negative or not
+    /// finite; a zero weight keeps the tile from being picked while others
+    /// remain.
+    pub fn set_weight(&mut self, tile_idx: usize, weight: f32) -> bool {
+        if tile_idx >= self.weights.len() || !weight.is_finite() || weight < 0.0 {
+            return false;
+        }
+        self.weights[tile_idx] = weight;
+        self.update_weight_cache();
+        true
+    }
+
+    /// Recomputes every weight from the extracted frequency counts: mode `0`
+    /// uses the counts as-is, `1` their square roots to compress the range so
+    /// rare patterns show up more, and `2` gives every pattern weight `1`.
+    /// Replaces any `set_weight` overrides. Returns `false` and changes
+    /// nothing for an unknown mode.
+    pub fn normalize_weights(&mut self, mode: u32) -> bool {
+        let normalize: fn(f32) -> f32 = match mode {
+            0 => |count| count,
+            1 => f32::sqrt,
+            2 => |_| 1.0,
+            _ => return false,
+        };
+        self.weights = self.pattern_counts.iter().map(|&count| normalize(count)).collect();
+        self.update_weight_cache();
+        true
+    }
+
+    pub fn total_cells(&self) -> usize {
+        self.matrix.len()
+    }
+
+    /// Fraction of active cells collapsed so far, from `0.0` to `1.0`, so it
+    /// reaches `1.0` exactly when `is_complete` does.
+    pub fn progress(&self) -> f32 {
+        let active = self.active.iter().filter(|&&a| a).count();
+        if active == 0 {
+            return 1.0;
+        }
+        self.get_collapsed_count() as f32 / active as f32
+    }
+
+    /// Number of tiles still possible at each cell, in row-major order:
+    /// `1` for collapsed cells, `0` for contradicted ones.
+    pub fn get_entropy_map(&self) -> Vec<u32> {
+        self.entropy_map.iter().map(|&e| e as u32).collect()
+    }
+
+    /// Tile indices still possible at `(row, col)`, in ascending order.
+    /// Empty if the position is out of range or the cell has contradicted.
+    pub fn possible_tiles(&self, row: usize, col: usize) -> Vec<u32> {
+        if row >= self.output_height || col >= self.output_width {
+            return Vec::new();
+        }
+        self.matrix.get(row * self.output_width + col).iter().map(|t| t as u32).collect()
+    }
+
+    /// Chance of each tile being chosen at `(row, col)`, indexed by tile:
+    /// its weight over the total weight of the tiles still allowed there,
+    /// and `0` for the rest. Allowed tiles share evenly if their weights sum
+    /// to zero, as in the solver. Empty if the position is out of range,
+    /// all zeros if the cell has contradicted.
+    pub fn get_probabilities(&self, row: usize, col: usize) -> Vec<f32> {
+        if row >= self.output_height || col >= self.output_width {
+            return Vec::new();
+        }
+        let mask = &*self.matrix.get(row * self.output_width + col);
+        let total: f32 = mask.iter().map(|i| self.weights[i]).sum();
+        let even = !total.is_finite() || total <= 0.0;
+        let count = mask.count_ones() as f32;
+
+        let mut probabilities = vec![0.0; self.tiles.len()];
+        for i in mask.iter() {
+            probabilities[i] = if even { 1.0 / count } else { self.weights[i] / total };
+        }
+        probabilities
+    }
+
+    /// Label each pattern places in the output, indexed by tile: the top-left
+    /// one, for engines built with `from_labels`. Combined with
+    /// `get_tile_grid` this gives the output label grid.
+    pub fn get_tile_labels(&self) -> Vec<i32> {
+        self.tiles.iter().map(|tile| color_label(tile[0][0])).collect()
+    }
+
+    /// Chosen tile index per cell in row-major order (matching
+    /// `get_image_data`), or `-1` where the cell is still in superposition.
+    pub fn get_tile_grid(&self) -> Vec<i32> {
+        self.matrix
+            .iter()
+            .map(|mask| mask.single_tile().map_or(-1, |t| t as i32))
+            .collect()
+    }
+
+    pub fn get_image_data(&self) -> Vec<u8> {
+        let mut data = vec![0u8; self.matrix.len() * 4];
+        self.fill_image_data(&mut data);
+        data
+    }
+
+    /// Cells whose `get_image_data` color may have changed since the previous
+    /// call, as `{ index, r, g, b, a }` objects with `index` the row-major
+    /// cell. The first call lists every cell. Patching a kept `ImageData`
+    /// with these instead of fetching a full frame cuts the copy across the
+    /// wasm boundary when only a few cells change per step.
+    pub fn get_image_deltas(&mut self) -> JsValue {
+        let mut dirty = std::mem::take(&mut self.dirty);
+        dirty.sort_unstable();
+        let mut deltas = Vec::with_capacity(dirty.len());
+        for &index in &dirty {
+            self.dirty_flags[index] = false;
+            let Color { r, g, b, a } = self.cell_color(index);
+            deltas.push(PixelDelta { index, r, g, b, a });
+        }
+        dirty.clear();
+        self.dirty = dirty;
+        serde_wasm_bindgen::to_value(&deltas).expect("pixel deltas always convert")
+    }
+
+    /// The current `get_image_data` image encoded as an 8-bit RGBA PNG of
+    /// `output_width` x `output_height` pixels, ready to download.
+    pub fn to_png(&self) -> Result<Vec<u8>, WfcError> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.output_width as u32, self.output_height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let encoding = |e: png::EncodingError| WfcError::Encoding { reason: e.to_string() };
+        let mut writer = encoder.write_header().map_err(encoding)?;
+        writer.write_image_data(&self.get_image_data()).map_err(encoding)?;
+        writer.finish().map_err(encoding)?;
+        Ok(png)
+    }
+
+    /// Writes the same RGBA data as `get_image_data` into `out`, which must
+    /// be exactly `total_cells() * 4` bytes, so a render loop can reuse one
+    /// buffer (e.g. the `data` of a kept `ImageData`) instead of allocating
+    /// per frame.
+    pub fn write_image_data(&self, out: &mut [u8]) -> Result<(), WfcError> {
+        let expected = self.matrix.len() * 4;
+        if out.len() != expected {
+            return Err(WfcError::BufferSizeMismatch { expected, found: out.len() });
+        }
+        self.fill_image_data(out);
+        Ok(())
+    }
+
+    fn fill_image_data(&self, out: &mut [u8]) {
+        for (idx, pixel) in out.chunks_exact_mut(4).enumerate().take(self.matrix.len()) {
+            let color = self.cell_color(idx);
+            pixel.copy_from_slice(&[color.r, color.g, color.b, color.a]);
+        }
+    }
+
+    /// RGBA buffer reconstructing the output at pixel resolution. Neighboring
+    /// patterns overlap, so each collapsed cell contributes only the top-left
+    /// `stride x stride` block of its pattern, and the cells of the last row
+    /// and column the rest of it; tile-based engines draw every tile whole.
+    /// Cells still in superposition show their blended display color. The
+    /// image is `(output_width - 1) * stride + tile_width` pixels wide and
+    /// `(output_height - 1) * stride + tile_height` tall.
+    pub fn get_full_image_data(&self) -> Vec<u8> {
+        let (tw, th) = (self.tile_width, self.tile_height);
+        let (sy, sx) = self.cell_step;
+        let width = (self.output_width - 1) * sx + tw;
+        let height = (self.output_height - 1) * sy + th;
+        let mut data = vec![0u8; width * height * 4];
+
+        for (idx, mask) in self.matrix.iter().enumerate() {
+            let row = idx / self.output_width;
+            let col = idx % self.output_width;
+            let tile = mask.single_tile().filter(|_| self.active[idx]).map(|t| &self.tiles[t]);
+            let blended = self.cell_color(idx);
+            let rows = if row + 1 == self.output_height { th } else { sy };
+            let cols = if col + 1 == self.output_width { tw } else { sx };
+
+            for tr in 0..rows {
+                for tc in 0..cols {
+                    let color = tile.map_or(blended, |t| t[tr][tc]);
+                    let offset = ((row * sy + tr) * width + col * sx + tc) * 4;
+                    data[offset] = color.r;
+                    data[offset + 1] = color.g;
+                    data[offset + 2] = color.b;
+                    data[offset + 3] = color.a;
+                }
+            }
+        }
+        data
+    }
+
+    // Display color of cell `idx`, from the cache when its mask hasn't
+    // changed since the last render.
+    fn cell_color(&self, idx: usize) -> Color {
+        if !self.active[idx] {
+            return Color { r: 0, g: 0, b: 0, a: 0 };
+        }
+        let mut cache = self.display_colors.borrow_mut();
+        *cache[idx].get_or_insert_with(|| self.get_display_color(&self.matrix.get(idx)))
+    }
+
+    // Candidate colors blended by pattern weight, so the preview leans
+    // towards the likely outcome. Falls back to an even blend if every
+    // candidate has zero weight. `DisplayMode::MostLikely` shows the
+    // heaviest candidate's color instead.
+    fn get_display_color(&self, mask: &TileMask) -> Color {
+        if mask.is_empty() {
+            return Color { r: 255, g: 0, b: 255, a: 255 };
+        }
+        if self.display_mode == DisplayMode::MostLikely {
+            // Ties go to the lowest index.
+            let best = mask.iter().reduce(|b, i| if self.weights[i] > self.weights[b] { i } else { b });
+            return self.tile_colors[best.expect("mask is not empty")];
+        }
+
+        let total_weight: f32 = mask.iter().map(|i| self.weights[i]).sum();
+        let even = !total_weight.is_finite() || total_weight <= 0.0;
+        let mut r = 0.0f32;
+        let mut g = 0.0f32;
+        let mut b = 0.0f32;
+        let mut a = 0.0f32;
+        let mut total = 0.0f32;
+
+        for i in mask.iter() {
+            let c = self.tile_colors[i];
+            let w = if even { 1.0 } else { self.weights[i] };
+            r += c.r as f32 * w;
+            g += c.g as f32 * w;
+            b += c.b as f32 * w;
+            a += c.a as f32 * w;
+            total += w;
+        }
+
+        Color {
+            r: (r / total).round() as u8,
+            g: (g / total).round() as u8,
+            b: (b / total).round() as u8,
+            a: (a / total).round() as u8,
+        }
+    }
+}
+
+/// Number of distinct patterns `new` would extract from `input_colors` with
+/// the given `tile_size` and `symmetry`, without building an engine. Lets a
+/// UI warn about inputs that would solve slowly before committing to one.
+#[wasm_bindgen]
+pub fn count_patterns(input_colors: JsValue, tile_size: usize, symmetry: u8) -> Result<usize, WfcError> {
+    let input: Vec<Vec<Color>> = serde_wasm_bindgen::from_value(input_colors)?;
+    let options = EngineOptions { tile_size, symmetry, ..EngineOptions::default() };
+    Ok(extract_patterns(&[&input], &options)?.tiles.len())
+}
+
+// Validates the extraction side of `options` and returns the patterns of
+// `samples`, as shared by every example-based constructor.
+fn extract_patterns(samples: &[&[Vec<Color>]], options: &EngineOptions) -> Result<Patterns, WfcError> {
+    if samples.is_empty() {
+        return Err(WfcError::EmptyInput);
+    }
+    let tile_width = options.tile_width.unwrap_or(options.tile_size);
+    let tile_height = options.tile_height.unwrap_or(options.tile_size);
+    if tile_width == 0 || tile_height == 0 {
+        return Err(WfcError::InvalidTileSize { tile_width, tile_height });
+    }
+    for sample in samples {
+        validate_input(sample, tile_width, tile_height)?;
+    }
+    if options.symmetry > 2 {
+        return Err(WfcError::InvalidSymmetry { symmetry: options.symmetry });
+    }
+    if !matches!(options.rotations, 1 | 2 | 4) {
+        return Err(WfcError::InvalidRotations { rotations: options.rotations });
+    }
+    if let Some(flags) = options.transforms.filter(|&f| f & !(ROT90 | ROT180 | FLIP_H | FLIP_V) != 0) {
+        return Err(WfcError::InvalidTransforms { transforms: flags });
+    }
+    let min_side = tile_width.min(tile_height);
+    if options.stride == 0 || options.stride >= min_side.max(2) {
+        return Err(WfcError::InvalidStride { stride: options.stride, tile_size: min_side });
+    }
+    let diagonal = options.diagonal || options.smooth;
+    if options.hex && diagonal {
+        return Err(WfcError::HexWithDiagonal);
+    }
+
+    let mut transforms = transform_group(options.transform_flags());
+    if options.hex {
+        transforms.retain(|t| !t.reflect && t.quarter_turns % 2 == 0);
+    }
+    if tile_width != tile_height {
+        transforms.retain(|t| t.quarter_turns % 2 == 0);
+    }
+    let patterns = extract_tiles(samples, tile_width, tile_height, &transforms, options.min_count, options.max_patterns);
+    if patterns.tiles.is_empty() {
+        return Err(WfcError::NoPatterns { min_count: options.min_count });
+    }
+    Ok(patterns)
+}
+
+// Checks the preconditions of `extract_tiles`: a non-empty, rectangular
+// input at least as large as the tile in each direction.
+fn validate_input(input: &[Vec<Color>], tile_width: usize, tile_height: usize) -> Result<(), WfcError> {
+    let width = input.first().map_or(0, Vec::len);
+    if width == 0 {
+        return Err(WfcError::EmptyInput);
+    }
+    if let Some((row, r)) = input.iter().enumerate().find(|(_, r)| r.len() != width) {
+        return Err(WfcError::NonRectangularInput { row, expected: width, found: r.len() });
+    }
+    if tile_width > width || tile_height > input.len() {
+        return Err(WfcError::TileLargerThanInput { tile_width, tile_height, width, height: input.len() });
+    }
+    Ok(())
+}
+
+// Patterns found by `extract_tiles`, with their weights and the input
+// borders each one touched.
+struct Patterns {
+    tiles: Vec<Tile>,
+    weights: Vec<f32>,
+    edges: Vec<Edges>,
+}
+
+// Counts the windows of every sample and their transformed variants. The
+// samples must have passed `validate_input`; jagged rows would be indexed
+// out of bounds.
+fn extract_tiles(
+    samples: &[&[Vec<Color>]],
+    tile_width: usize,
+    tile_height: usize,
+    transforms: &[Transform],
+    min_count: usize,
+    max_patterns: usize,
+) -> Patterns {
+    // Index into `tiles`/`weights` while counting; the final order is fixed
+    // by the sort below, never by HashMap iteration order.
+    let mut tile_index: HashMap<Tile, usize> = HashMap::new();
+    let mut tiles = Vec::new();
+    let mut weights = Vec::new();
+    let mut edges: Vec<Edges> = Vec::new();
+
+    for input in samples {
+        let rows = input.len();
+        let cols = input[0].len();
+
+        for r in 0..=(rows - tile_height) {
+            for c in 0..=(cols - tile_width) {
+                let mut tile = Vec::with_capacity(tile_height);
+                for tr in 0..tile_height {
+                    let mut row = Vec::with_capacity(tile_width);
+                    for tc in 0..tile_width {
+                        row.push(input[r + tr][c + tc]);
+                    }
+                    tile.push(row);
+                }
+                let touched = [r == 0, r == rows - tile_height, c == 0, c == cols - tile_width];
+
+                // Variants that coincide, e.g. the rotations of a symmetric
+                // window, share one key and so merge into a single pattern
+                // whose weight counts each of them.
+                for &transform in transforms {
+                    let variant = transform.apply_tile(&tile);
+                    let idx = *tile_index.entry(variant.clone()).or_insert_with(|| {
+                        tiles.push(variant);
+                        weights.push(0.0);
+                        edges.push([false; 4]);
+                        tiles.len() - 1
+                    });
+                    weights[idx] += 1.0;
+                    for (seen, now) in edges[idx].iter_mut().zip(transform.apply_edges(touched)) {
+                        *seen |= now;
+                    }
+                }
+            }
+        }
+    }
+
+    // Sort by the flattened RGBA bytes so a pattern's index depends only on
+    // its pixels, keeping `set_cell`/`ban_tile` indices and saved states
+    // stable across runs.
+    // Patterns rarer than `min_count` are dropped first, so they never take
+    // an index.
+    let mut order: Vec<usize> = (0..tiles.len()).filter(|&i| weights[i] >= min_count as f32).collect();
+    order.sort_by_cached_key(|&i| tile_key(&tiles[i]));
+    // Then all but the `max_patterns` heaviest, ties going to the lower key.
+    if max_patterns > 0 && order.len() > max_patterns {
+        let mut by_weight = order.clone();
+        by_weight.sort_by(|&a, &b| weights[b].total_cmp(&weights[a]));
+        let mut keep = vec![false; tiles.len()];
+        for &i in &by_weight[..max_patterns] {
+            keep[i] = true;
+        }
+        order.retain(|&i| keep[i]);
+    }
+    Patterns {
+        tiles: order.iter().map(|&i| tiles[i].clone()).collect(),
+        weights: order.iter().map(|&i| weights[i]).collect(),
+        edges: order.iter().map(|&i| edges[i]).collect(),
+    }
+}
+
+// Per tile and direction, the sorted indices of the tiles found next to it
+// in `samples`, `stride` pixels away. Only untransformed windows count, so a
+// direction keeps its meaning in the example.
+fn observed_neighbors(
+    samples: &[&[Vec<Color>]],
+    tiles: &[Tile],
+    directions: &[(isize, isize)],
+    stride: usize,
+) -> Vec<Vec<Vec<usize>>> {
+    let (tile_width, tile_height) = (tiles[0][0].len(), tiles[0].len());
+    let index: HashMap<&Tile, usize> = tiles.iter().enumerate().map(|(i, t)| (t, i)).collect();
+    let mut seen = vec![vec![Vec::new(); directions.len()]; tiles.len()];
+
+    for input in samples {
+        let rows = input.len() - tile_height + 1;
+        let cols = input[0].len() - tile_width + 1;
+        // Tile index of each window, `None` where `min_count` dropped it.
+        let windows: Vec<Option<usize>> = (0..rows * cols)
+            .map(|w| {
+                let (r, c) = (w / cols, w % cols);
+                let tile: Tile = input[r..r + tile_height].iter().map(|row| row[c..c + tile_width].to_vec()).collect();
+                index.get(&tile).copied()
+            })
+            .collect();
+
+        for (w, &tile) in windows.iter().enumerate() {
+            let Some(tile) = tile else { continue };
+            let (r, c) = ((w / cols) as isize, (w % cols) as isize);
+            for (dir, &(dr, dc)) in directions.iter().enumerate() {
+                let (nr, nc) = (r + dr * stride as isize, c + dc * stride as isize);
+                if nr < 0 || nc < 0 || nr >= rows as isize || nc >= cols as isize {
+                    continue;
+                }
+                if let Some(n) = windows[nr as usize * cols + nc as usize] {
+                    seen[tile][dir].push(n);
+                }
+            }
+        }
+    }
+    for list in seen.iter_mut().flatten() {
+        list.sort_unstable();
+        list.dedup();
+    }
+    seen
+}
+
+fn tile_key(tile: &Tile) -> Vec<u8> {
+    tile.iter().flatten().flat_map(|c| [c.r, c.g, c.b, c.a]).collect()
+}
+
+// A symmetry applied to extracted patterns: `quarter_turns` clockwise
+// rotations, followed by a horizontal mirror if `reflect` is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Transform {
+    quarter_turns: usize,
+    reflect: bool,
+}
+
+impl Transform {
+    const IDENTITY: Transform = Transform { quarter_turns: 0, reflect: false };
+
+    // `self` followed by `next`. A mirror reverses the direction of any
+    // rotation applied after it, which is where the sign comes from.
+    fn then(self, next: Transform) -> Transform {
+        let turns = if self.reflect { 4 - next.quarter_turns } else { next.quarter_turns };
+        Transform {
+            quarter_turns: (self.quarter_turns + turns) % 4,
+            reflect: self.reflect != next.reflect,
+        }
+    }
+
+    fn apply_tile(self, tile: &Tile) -> Tile {
+        let mut out = tile.clone();
+        for _ in 0..self.quarter_turns {
+            out = rotate_tile(&out);
+        }
+        if self.reflect {
+            out = reflect_tile(&out);
+        }
+        out
+    }
+
+    // Moves border flags along with the pixels, so a side of the transformed
+    // tile is flagged iff it came from a side that touched the input border.
+    fn apply_edges(self, edges: Edges) -> Edges {
+        let [mut up, mut down, mut left, mut right] = edges;
+        for _ in 0..self.quarter_turns {
+            (up, right, down, left) = (left, up, right, down);
+        }
+        if self.reflect {
+            std::mem::swap(&mut left, &mut right);
+        }
+        [up, down, left, right]
+    }
+}
+
+// Every transform reachable by combining those selected in `flags`, with
+// the identity first.
+fn transform_group(flags: u8) -> Vec<Transform> {
+    let generators: Vec<Transform> = [
+        (ROT90, Transform { quarter_turns: 1, reflect: false }),
+        (ROT180, Transform { quarter_turns: 2, reflect: false }),
+        (FLIP_H, Transform { quarter_turns: 0, reflect: true }),
+        (FLIP_V, Transform { quarter_turns: 2, reflect: true }),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .map(|&(_, t)| t)
+    .collect();
+
+    let mut group = vec![Transform::IDENTITY];
+    let mut i = 0;
+    while i < group.len() {
+        for &g in &generators {
+            let t = group[i].then(g);
+            if !group.contains(&t) {
+                group.push(t);
+            }
+        }
+        i += 1;
+    }
+    group
+}
+
+// Lossless stand-in color for a `from_labels` label. The high byte goes to
+// alpha inverted, so small labels come out opaque.
+fn label_color(label: i32) -> Color {
+    let [r, g, b, high] = label.to_le_bytes();
+    Color { r, g, b, a: !high }
+}
+
+fn color_label(c: Color) -> i32 {
+    i32::from_le_bytes([c.r, c.g, c.b, !c.a])
+}
+
+// Color standing for each tile in previews, per `ColorSampling`.
+fn sample_colors(tiles: &[Tile], sampling: ColorSampling) -> Vec<Color> {
+    tiles
+        .iter()
+        .map(|tile| match sampling {
+            ColorSampling::TopLeft => tile[0][0],
+            ColorSampling::Center => tile[tile.len() / 2][tile[0].len() / 2],
+            ColorSampling::Average => {
+                let n = (tile.len() * tile[0].len()) as u32;
+                let mut sum = [0u32; 4];
+                for c in tile.iter().flatten() {
+                    for (s, v) in sum.iter_mut().zip([c.r, c.g, c.b, c.a]) {
+                        *s += v as u32;
+                    }
+                }
+                let avg = |s: u32| ((s + n / 2) / n) as u8;
+                Color { r: avg(sum[0]), g: avg(sum[1]), b: avg(sum[2]), a: avg(sum[3]) }
+            }
+        })
+        .collect()
+}
+
+// Turns `tile` a quarter clockwise; a `w x h` tile comes out `h x w`.
+fn rotate_tile(tile: &Tile) -> Tile {
+    let rows = tile.len();
+    let cols = tile[0].len();
+    let mut new_tile = vec![vec![Color { r: 0, g: 0, b: 0, a: 0 }; rows]; cols];
+    for r in 0..rows {
+        for c in 0..cols {
+            new_tile[c][rows - 1 - r] = tile[r][c];
+        }
+    }
+    new_tile
+}
+
+fn reflect_tile(tile: &Tile) -> Tile {
+    tile.iter()
+        .map(|row| row.iter().rev().copied().collect())
+        .collect()
+}
+
+// Neighbor cells sit `stride` pixels apart, so each direction is scaled by it
+// before comparing the overlap.
+fn compute_adjacencies(tiles: &[Tile], directions: &[(isize, isize)], stride: usize) -> Vec<Vec<TileMask>> {
+    let stride = stride as isize;
+    // For each direction, bucket the tiles by the part a neighbor there
+    // overlaps; a tile's compatible neighbors are then the bucket matching
+    // its own side of that overlap. This is linear in the tile count, where
+    // comparing every pair of tiles pixel by pixel would be quadratic.
+    // Building the keys and looking them up are independent per tile, so
+    // those are the steps spread across threads.
+    let tile_keys = |tile: &Tile| -> Vec<(Vec<Color>, Vec<Color>)> {
+        directions
+            .iter()
+            .map(|&(dr, dc)| {
+                let (dr, dc) = (dr * stride, dc * stride);
+                (overlap_key(tile, dr, dc, false), overlap_key(tile, dr, dc, true))
+            })
+            .collect()
+    };
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    let keys: Vec<Vec<(Vec<Color>, Vec<Color>)>> = {
+        use rayon::prelude::*;
+        tiles.par_iter().map(tile_keys).collect()
+    };
+    #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+    let keys: Vec<Vec<(Vec<Color>, Vec<Color>)>> = tiles.iter().map(tile_keys).collect();
+
+    let mut buckets: Vec<HashMap<&[Color], TileMask>> = vec![HashMap::new(); directions.len()];
+    for (j, tile_keys) in keys.iter().enumerate() {
+        for (bucket, (_, as_neighbor)) in buckets.iter_mut().zip(tile_keys) {
+            bucket.entry(as_neighbor.as_slice()).or_insert_with(|| TileMask::empty(tiles.len())).insert(j);
+        }
+    }
+
+    let neighbors = |tile_keys: &Vec<(Vec<Color>, Vec<Color>)>| -> Vec<TileMask> {
+        tile_keys
+            .iter()
+            .zip(&buckets)
+            .map(|((own, _), bucket)| bucket.get(own.as_slice()).cloned().unwrap_or_else(|| TileMask::empty(tiles.len())))
+            .collect()
+    };
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    let adj: Vec<Vec<TileMask>> = {
+        use rayon::prelude::*;
+        keys.par_iter().map(neighbors).collect()
+    };
+    #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+    let adj: Vec<Vec<TileMask>> = keys.iter().map(neighbors).collect();
+    debug_assert!(adjacencies_symmetric(directions, &adj));
+    adj
+}
+
+// Whether every "`j` may sit at offset `d` from `i`" has the matching
+// "`i` may sit at offset `-d` from `j`". Propagation relies on this to prune
+// the same way whichever of two neighbors changed first.
+fn adjacencies_symmetric(directions: &[(isize, isize)], adj: &[Vec<TileMask>]) -> bool {
+    directions.iter().enumerate().all(|(dir, &(dr, dc))| {
+        let Some(opposite) = directions.iter().position(|&d| d == (-dr, -dc)) else {
+            return false;
+        };
+        adj.iter()
+            .enumerate()
+            .all(|(i, dirs)| dirs[dir].iter().all(|j| adj[j][opposite].contains(i)))
+    })
+}
+
+// Adjacency table of Wang tiles with `[n, e, s, w]` edge codes: a tile fits
+// next to another wherever their facing codes are equal.
+fn wang_adjacencies(edges: &[[u32; 4]]) -> Vec<Vec<TileMask>> {
+    // Side of a tile facing each of `DIRECTIONS`, and the one facing back.
+    const FACING: [(usize, usize); 4] = [(0, 2), (2, 0), (3, 1), (1, 3)];
+    let adj: Vec<Vec<TileMask>> = edges
+        .iter()
+        .map(|a| {
+            FACING
+                .iter()
+                .map(|&(out, back)| {
+                    let mut mask = TileMask::empty(edges.len());
+                    for (j, b) in edges.iter().enumerate() {
+                        if a[out] == b[back] {
+                            mask.insert(j);
+                        }
+                    }
+                    mask
+                })
+                .collect()
+        })
+        .collect();
+    debug_assert!(adjacencies_symmetric(&DIRECTIONS, &adj));
+    adj
 }
 
-fn can_overlap(t1: &Tile, t2: &Tile, dr: isize, dc: isize) -> bool {
-    let size = t1.len() as isize;
-    for r1 in 0..size {
-        for c1 in 0..size {
-            let r2 = r1 + dr;
-            let c2 = c1 + dc;
-            if r2 >= 0 && r2 < size && c2 >= 0 && c2 < size {
-                if t1[r1 as usize][c1 as usize] != t2[r2 as usize][c2 as usize] {
-                    return false;
+// Each mask of an adjacency table as a sorted list of tile indices.
+fn adjacency_lists(adjacencies: &[Vec<TileMask>]) -> Vec<Vec<Vec<usize>>> {
+    adjacencies
+        .iter()
+        .map(|dirs| dirs.iter().map(|mask| mask.iter().collect()).collect())
+        .collect()
+}
+
+// Adjacency masks for `from_tiles_and_rules`, in the layout of
+// `compute_adjacencies`, after checking the tiles, weights and rules agree.
+fn rule_adjacencies(
+    tiles: &[Tile],
+    weights: &[f32],
+    rules: &[(usize, usize, usize)],
+) -> Result<Vec<Vec<TileMask>>, WfcError> {
+    let first = tiles.first().ok_or(WfcError::EmptyInput)?;
+    validate_input(first, 1, 1)?;
+    if let Some(i) = tiles.iter().position(|t| t.len() != first.len() || t.iter().any(|r| r.len() != first[0].len())) {
+        return Err(WfcError::InvalidArgument { reason: format!("tile {} differs in size from tile 0", i) });
+    }
+    if weights.len() != tiles.len() {
+        return Err(WfcError::InvalidArgument {
+            reason: format!("{} weights given for {} tiles", weights.len(), tiles.len()),
+        });
+    }
+    if let Some(i) = weights.iter().position(|w| !w.is_finite() || *w < 0.0) {
+        return Err(WfcError::InvalidArgument { reason: format!("weight {} is negative or not finite", i) });
+    }
+
+    let mut adj = vec![vec![TileMask::empty(tiles.len()); DIRECTIONS.len()]; tiles.len()];
+    for &(a, b, dir) in rules {
+        if let Some(&tile_idx) = [a, b].iter().find(|&&t| t >= tiles.len()) {
+            return Err(WfcError::TileOutOfRange { tile_idx, tile_count: tiles.len() });
+        }
+        if dir >= DIRECTIONS.len() {
+            return Err(WfcError::InvalidArgument { reason: format!("direction must be 0 to 3, got {}", dir) });
+        }
+        // Up/down and left/right are adjacent in `DIRECTIONS`, so the
+        // opposite direction differs only in the lowest bit.
+        adj[a][dir].insert(b);
+        adj[b][dir ^ 1].insert(a);
+    }
+    Ok(adj)
+}
+
+// Pixels of `tile` inside the overlap with a neighbor at offset `(dr, dc)`:
+// from the tile's own side, or with `as_neighbor` from the side of the tile
+// placed at that offset. Two tiles fit together iff their keys are equal,
+// since both list the overlap in the same order. `t2`'s pixel `(r, c)` lies
+// on `t1`'s pixel `(r + dr, c + dc)`.
+fn overlap_key(tile: &Tile, dr: isize, dc: isize, as_neighbor: bool) -> Vec<Color> {
+    let rows = tile.len() as isize;
+    let cols = tile[0].len() as isize;
+    let mut key = Vec::new();
+    for r1 in 0..rows {
+        for c1 in 0..cols {
+            let r2 = r1 - dr;
+            let c2 = c1 - dc;
+            if r2 >= 0 && r2 < rows && c2 >= 0 && c2 < cols {
+                let (r, c) = if as_neighbor { (r2, c2) } else { (r1, c1) };
+                key.push(tile[r as usize][c as usize]);
+            }
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Opaque gray image from rows of digits, one pixel per character.
+    fn image(rows: &[&str]) -> Vec<Vec<Color>> {
+        rows.iter()
+            .map(|row| {
+                row.bytes()
+                    .map(|b| {
+                        let v = (b - b'0') * 60;
+                        Color { r: v, g: v, b: v, a: 255 }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn engine(input: &[Vec<Color>], options: EngineOptions) -> WfcEngine {
+        WfcEngine::build(&[input], &options).expect("valid test input")
+    }
+
+    const ROOMS: [&str; 6] = ["000000", "011110", "010010", "010010", "011110", "000000"];
+
+    #[test]
+    fn wrap_joins_opposite_edges() {
+        let mut e = engine(&image(&ROOMS), EngineOptions {
+            output_size: 12,
+            tile_size: 2,
+            wrap: true,
+            seed: Some(3),
+            ..EngineOptions::default()
+        });
+        assert!(e.solve_with_retries(8));
+        let grid = e.get_tile_grid();
+        let (w, h) = (e.output_width, e.output_height);
+        for r in 0..h {
+            let (last, first) = (grid[r * w + w - 1] as usize, grid[r * w] as usize);
+            assert!(e.adjacencies[last][3].contains(first), "row {}", r);
+        }
+        for c in 0..w {
+            let (last, first) = (grid[(h - 1) * w + c] as usize, grid[c] as usize);
+            assert!(e.adjacencies[last][1].contains(first), "column {}", c);
+        }
+    }
+
+    #[test]
+    fn full_image_reconstructs_overlapping_patterns() {
+        for (tile_size, stride) in [(2, 1), (3, 2)] {
+            let mut e = engine(&image(&ROOMS), EngineOptions {
+                output_size: 8,
+                tile_size,
+                stride,
+                seed: Some(5),
+                ..EngineOptions::default()
+            });
+            assert!(e.solve_with_retries(8));
+            let data = e.get_full_image_data();
+            let width = 7 * stride + tile_size;
+            assert_eq!(data.len(), width * width * 4);
+            // Every cell's whole pattern shows up at its position in the image.
+            let grid = e.get_tile_grid();
+            for (idx, &tile) in grid.iter().enumerate() {
+                let (row, col) = (idx / 8 * stride, idx % 8 * stride);
+                for (tr, pixels) in e.tiles[tile as usize].iter().enumerate() {
+                    for (tc, color) in pixels.iter().enumerate() {
+                        let offset = ((row + tr) * width + col + tc) * 4;
+                        assert_eq!(data[offset..offset + 4], [color.r, color.g, color.b, color.a]);
+                    }
+                }
+            }
+        }
+    }
+
+    // Tile-based engine over three 1x1 tiles whose rules admit solutions,
+    // but let propagation accept choices that dead-end a few cells away.
+    fn trap_tiles(options: EngineOptions) -> WfcEngine {
+        let tiles: Vec<Tile> = (0..3).map(|c| vec![vec![Color { r: c, g: 0, b: 0, a: 255 }]]).collect();
+        let weights = vec![1.0; 3];
+        let rules = [(0, 0, 1), (0, 0, 3), (0, 1, 1), (0, 2, 3), (1, 1, 1), (1, 1, 3), (1, 2, 3), (2, 1, 1), (2, 1, 3)];
+        let adjacencies = rule_adjacencies(&tiles, &weights, &rules).unwrap();
+        let seen = adjacency_lists(&adjacencies);
+        let patterns = Patterns { tiles, weights, edges: vec![[true; 4]; 3] };
+        WfcEngine::assemble(patterns, &DIRECTIONS, adjacencies, seen, (1, 1), &options).unwrap()
+    }
+
+    #[test]
+    fn backtracking_recovers_without_reset() {
+        let mut e = trap_tiles(EngineOptions {
+            output_size: 6,
+            seed: Some(1),
+            max_backtrack_depth: 10_000,
+            contradiction_strategy: ContradictionStrategy::Backtrack,
+            ..EngineOptions::default()
+        });
+        e.run_to_completion();
+        assert!(e.is_complete());
+        let stats = e.get_stats();
+        assert!(stats.backtracks > 0);
+        assert_eq!((stats.local_resets, stats.full_resets), (0, 0));
+    }
+
+    #[test]
+    fn load_state_rejects_corrupted_masks() {
+        let mut e = engine(&image(&ROOMS), EngineOptions { output_size: 6, tile_size: 2, seed: Some(2), ..EngineOptions::default() });
+        let saved = e.save_state();
+        let before = e.matrix.clone();
+        let count = e.tiles.len();
+        let corruptions = [TileMask::empty(count), TileMask::full(count + 64), TileMask::single(64, 63)];
+        for mask in corruptions {
+            let mut state: SavedState = bincode::deserialize(&saved).unwrap();
+            state.matrix[3] = mask;
+            let bytes = bincode::serialize(&state).unwrap();
+            assert!(matches!(e.load_state(&bytes), Err(WfcError::InvalidState { .. })));
+            assert_eq!(e.matrix, before);
+        }
+    }
+
+    #[test]
+    fn malformed_input_is_an_error_not_a_panic() {
+        let options = EngineOptions { tile_size: 3, ..EngineOptions::default() };
+        let build = |input: &[Vec<Color>]| WfcEngine::build(&[input], &options).err();
+        assert_eq!(build(&[]), Some(WfcError::EmptyInput));
+        assert_eq!(build(&[vec![]]), Some(WfcError::EmptyInput));
+        assert!(matches!(build(&image(&["000", "00", "000"])), Some(WfcError::NonRectangularInput { .. })));
+        assert!(matches!(build(&image(&["00", "00"])), Some(WfcError::TileLargerThanInput { .. })));
+    }
+
+    #[test]
+    fn jagged_input_reports_the_bad_row() {
+        let input = image(&["0110", "0110", "011", "0110"]);
+        let err = WfcEngine::build(&[&input], &EngineOptions::default()).err();
+        assert_eq!(err, Some(WfcError::NonRectangularInput { row: 2, expected: 4, found: 3 }));
+    }
+
+    #[test]
+    fn pattern_weights_sum_across_samples() {
+        let small = image(&["01", "23"]);
+        let large = image(&["012", "234", "012"]);
+        let patterns = extract_tiles(&[&small, &large], 2, 2, &[Transform::IDENTITY], 0, 0);
+        let shared = patterns.tiles.iter().position(|t| *t == small).unwrap();
+        // Once in `small`, once at the top-left of `large`.
+        assert_eq!(patterns.weights[shared], 2.0);
+        assert_eq!(patterns.weights.iter().sum::<f32>(), 5.0);
+    }
+
+    #[test]
+    fn same_seed_gives_same_output() {
+        let options = EngineOptions { output_size: 16, tile_size: 2, wrap: true, seed: Some(42), ..EngineOptions::default() };
+        let run = || {
+            let mut e = engine(&image(&ROOMS), options.clone());
+            assert!(e.solve_with_retries(8));
+            (e.get_tile_grid(), e.get_stats())
+        };
+        assert_eq!(run(), run());
+    }
+
+    // Every pixel distinct, so each window is its own pattern.
+    fn distinct_pixels(rows: usize, cols: usize) -> Vec<Vec<Color>> {
+        (0..rows)
+            .map(|r| (0..cols).map(|c| Color { r: r as u8, g: c as u8, b: 0, a: 255 }).collect())
+            .collect()
+    }
+
+    #[test]
+    fn rectangular_tiles_extract_every_window() {
+        let input = distinct_pixels(4, 5);
+        let patterns = extract_tiles(&[&input], 2, 3, &[Transform::IDENTITY], 0, 0);
+        // (4 - 3 + 1) rows by (5 - 2 + 1) columns of 3-tall, 2-wide windows.
+        assert_eq!(patterns.tiles.len(), 8);
+        assert!(patterns.tiles.iter().all(|t| t.len() == 3 && t[0].len() == 2));
+    }
+
+    #[test]
+    fn rectangular_tiles_drop_quarter_turns() {
+        let input = distinct_pixels(4, 5);
+        let options = EngineOptions { tile_width: Some(2), tile_height: Some(3), symmetry: 2, ..EngineOptions::default() };
+        let patterns = extract_patterns(&[&input], &options).unwrap();
+        // Identity, ROT180, FLIP_H and FLIP_V of each window; the quarter
+        // turns would be 3 wide and 2 tall.
+        assert_eq!(patterns.tiles.len(), 8 * 4);
+        assert!(patterns.tiles.iter().all(|t| t.len() == 3 && t[0].len() == 2));
+    }
+
+    #[test]
+    fn next_cell_matches_the_step() {
+        let mut e = engine(&image(&ROOMS), EngineOptions {
+            output_size: 9,
+            tile_size: 2,
+            seed: Some(4),
+            start_mode: StartMode::Center,
+            ..EngineOptions::default()
+        });
+        assert_eq!(e.next_cell().as_deref(), Some(&[4, 4][..]));
+        for _ in 0..20 {
+            let Some(next) = e.next_cell() else { break };
+            e.step();
+            let info = e.last_collapse.unwrap();
+            assert_eq!(*next, [info.row, info.col]);
+        }
+
+        e.set_propagation_budget(1);
+        e.step();
+        assert!(e.pending.is_some());
+        assert_eq!(e.next_cell(), None);
+    }
+
+    #[test]
+    fn all_zero_weights_still_collapse() {
+        let mut e = engine(&image(&ROOMS), EngineOptions { output_size: 8, tile_size: 2, seed: Some(6), ..EngineOptions::default() });
+        for tile in 0..e.tiles.len() {
+            assert!(e.set_weight(tile, 0.0));
+        }
+        assert!(e.step());
+        let info = e.last_collapse.unwrap();
+        let idx = info.row * e.output_width + info.col;
+        assert_eq!(e.matrix.get(idx).single_tile(), Some(info.tile));
+        // Rendering falls back to an even blend of the zero-weight candidates.
+        assert_eq!(e.get_image_data().len(), 8 * 8 * 4);
+    }
+
+    #[test]
+    fn progress_counts_only_active_cells() {
+        let mut e = engine(&image(&ROOMS), EngineOptions { output_size: 8, tile_size: 2, seed: Some(8), ..EngineOptions::default() });
+        let active: Vec<u8> = (0..64).map(|i| (i % 8 < 4) as u8).collect();
+        e.set_active_mask(&active).unwrap();
+        assert!(e.solve_with_retries(8));
+        assert!(e.is_complete());
+        assert_eq!(e.get_collapsed_count(), 32);
+        assert_eq!(e.progress(), 1.0);
+    }
+
+    #[test]
+    fn zero_tile_size_is_rejected() {
+        let input = image(&ROOMS);
+        for (tile_width, tile_height) in [(0, 2), (2, 0), (0, 0)] {
+            let options = EngineOptions { tile_width: Some(tile_width), tile_height: Some(tile_height), ..EngineOptions::default() };
+            let err = extract_patterns(&[&input], &options).err();
+            assert_eq!(err, Some(WfcError::InvalidTileSize { tile_width, tile_height }));
+        }
+        let err = WfcEngine::build_wang(&[[0; 4]], &[vec![]], 4, 0).err();
+        assert_eq!(err, Some(WfcError::InvalidTileSize { tile_width: 0, tile_height: 0 }));
+    }
+
+    #[test]
+    fn small_tilesets_store_a_word_per_cell() {
+        let e = engine(&image(&ROOMS), EngineOptions { output_size: 10, tile_size: 2, ..EngineOptions::default() });
+        assert!(e.tiles.len() <= 64);
+        assert_eq!(e.matrix.bytes(), 100 * 8);
+        assert!(matches!(MaskGrid::new(64, 4, &TileMask::full(64)), MaskGrid::Words(_)));
+        assert!(matches!(MaskGrid::new(65, 4, &TileMask::full(65)), MaskGrid::Masks(_)));
+
+        let masks = [TileMask::single(40, 39), TileMask::full(130)];
+        for mask in masks {
+            let bytes = bincode::serialize(&mask).unwrap();
+            assert_eq!(bincode::deserialize::<TileMask>(&bytes).unwrap(), mask);
+        }
+    }
+
+    #[test]
+    fn collapse_forced_finalizes_each_forced_cell_once() {
+        let mut e = trap_tiles(EngineOptions { output_size: 6, seed: Some(1), ..EngineOptions::default() });
+        e.enable_history();
+        // Under these rules a pin of tile 0 forces a whole block of cells.
+        assert!(e.set_cell(2, 2, 0));
+        let forced = e.get_collapsed_count() - 1;
+        assert!(forced > 0);
+        assert_eq!(e.collapse_forced(), forced);
+        assert_eq!(e.history.as_ref().unwrap().len(), forced);
+        assert!(e.last_collapse.is_some());
+        assert_eq!(e.collapse_forced(), 0);
+    }
+
+    #[test]
+    fn mutators_finish_pending_propagation() {
+        let options = EngineOptions { output_size: 10, tile_size: 2, seed: Some(4), ..EngineOptions::default() };
+        let mut reference = engine(&image(&ROOMS), options.clone());
+        reference.step();
+
+        let mut e = engine(&image(&ROOMS), options.clone());
+        e.set_propagation_budget(1);
+        e.step();
+        assert!(e.pending.is_some());
+        let saved = e.save_state();
+        assert!(e.pending.is_none());
+        assert_eq!(e.matrix, reference.matrix);
+        let mut loaded = engine(&image(&ROOMS), options.clone());
+        loaded.load_state(&saved).unwrap();
+        assert_eq!(loaded.matrix, reference.matrix);
+
+        let mut e = engine(&image(&ROOMS), options);
+        e.set_propagation_budget(1);
+        e.step();
+        assert!(e.pending.is_some());
+        e.ban_tile(0, 0, e.tiles.len() - 1);
+        assert!(e.pending.is_none());
+    }
+
+    #[test]
+    fn step_n_reports_completion_on_the_last_step() {
+        let mut e = engine(&image(&ROOMS), EngineOptions { output_size: 6, tile_size: 2, seed: Some(3), ..EngineOptions::default() });
+        while e.step_n(1) {
+            assert!(!e.is_complete());
+        }
+        assert!(e.is_complete());
+    }
+
+    #[test]
+    fn engine_rng_matches_small_rng() {
+        use rand::{RngCore, SeedableRng};
+        for seed in [0, 1, 42, u64::MAX] {
+            let mut ours = rng::EngineRng::new(seed);
+            let mut small = rand::rngs::SmallRng::seed_from_u64(seed);
+            for _ in 0..100 {
+                assert_eq!(ours.next_u64(), small.next_u64());
+            }
+        }
+    }
+
+    #[test]
+    fn load_state_resumes_the_generator_without_replaying() {
+        let options = EngineOptions { output_size: 8, tile_size: 2, seed: Some(9), ..EngineOptions::default() };
+        let mut e = engine(&image(&ROOMS), options.clone());
+        e.step_n(5);
+        let mut state: SavedState = bincode::deserialize(&e.save_state()).unwrap();
+        // A count this large would never finish replaying.
+        state.rng_draws = u64::MAX;
+        let mut loaded = engine(&image(&ROOMS), options.clone());
+        loaded.load_state(&bincode::serialize(&state).unwrap()).unwrap();
+        assert_eq!(loaded.rng.state(), e.rng.state());
+
+        state.rng_words = [0; 4];
+        let err = loaded.load_state(&bincode::serialize(&state).unwrap());
+        assert!(matches!(err, Err(WfcError::InvalidState { .. })));
+        assert!(matches!(e.set_rng_state(u64::MAX), Err(WfcError::InvalidArgument { .. })));
+    }
+
+    #[test]
+    fn pins_survive_a_reset_after_loading() {
+        let options = EngineOptions { output_size: 8, tile_size: 2, seed: Some(2), ..EngineOptions::default() };
+        let mut e = engine(&image(&ROOMS), options.clone());
+        assert!(e.set_cell(3, 4, 0));
+        let saved = e.save_state();
+
+        let mut loaded = engine(&image(&ROOMS), options);
+        loaded.load_state(&saved).unwrap();
+        // What a contradiction's full reset does.
+        loaded.clear_grid();
+        assert_eq!(loaded.matrix.get(3 * 8 + 4).single_tile(), Some(0));
+    }
+
+    #[test]
+    fn write_image_data_checks_the_buffer_length() {
+        let mut e = engine(&image(&ROOMS), EngineOptions { output_size: 6, tile_size: 2, seed: Some(1), ..EngineOptions::default() });
+        e.step_n(4);
+        let mut out = vec![0u8; 6 * 6 * 4];
+        e.write_image_data(&mut out).unwrap();
+        assert_eq!(out, e.get_image_data());
+        let mut short = vec![0u8; 6 * 6 * 4 - 1];
+        let err = e.write_image_data(&mut short);
+        assert_eq!(err, Err(WfcError::BufferSizeMismatch { expected: 144, found: 143 }));
+    }
+
+    #[test]
+    fn zero_output_size_is_rejected() {
+        for (width, height) in [(0, 0), (0, 4), (4, 0)] {
+            let options = EngineOptions {
+                output_width: Some(width),
+                output_height: Some(height),
+                tile_size: 2,
+                start_mode: StartMode::Center,
+                ..EngineOptions::default()
+            };
+            let err = WfcEngine::build(&[&image(&ROOMS)], &options).err();
+            assert_eq!(err, Some(WfcError::InvalidOutputSize { width, height }));
+        }
+    }
+
+    #[test]
+    fn stencil_survives_loading_into_a_new_engine() {
+        let options = EngineOptions { output_size: 8, tile_size: 2, seed: Some(8), ..EngineOptions::default() };
+        let mut e = engine(&image(&ROOMS), options.clone());
+        let active: Vec<u8> = (0..64).map(|i| (i % 8 < 4) as u8).collect();
+        e.set_active_mask(&active).unwrap();
+        e.step_n(3);
+        let saved = e.save_state();
+
+        let mut loaded = engine(&image(&ROOMS), options);
+        loaded.load_state(&saved).unwrap();
+        assert!(loaded.run_to_completion());
+        assert_eq!(loaded.get_collapsed_count(), 32);
+        let data = loaded.get_image_data();
+        for (idx, pixel) in data.chunks_exact(4).enumerate() {
+            assert_eq!(pixel[3] == 0, active[idx] == 0, "cell {}", idx);
+        }
+    }
+
+    #[test]
+    fn seed_edge_only_frees_minus_one() {
+        let mut e = engine(&image(&ROOMS), EngineOptions { output_size: 4, tile_size: 2, seed: Some(1), ..EngineOptions::default() });
+        let before = e.matrix.clone();
+        for bad in [-2, -1000] {
+            let err = e.seed_edge(0, &[-1, bad, -1, -1]);
+            assert!(matches!(err, Err(WfcError::InvalidArgument { .. })));
+            assert_eq!(e.matrix, before);
+        }
+        assert_eq!(e.seed_edge(0, &[-1; 4]), Ok(()));
+    }
+
+    // Regression test for the orientation of overlap checks: a neighbor in
+    // direction `(dr, dc)` must line up with the tile shifted by that much,
+    // not the opposite way.
+    #[test]
+    fn adjacency_respects_overlap_orientation() {
+        let a = image(&["01", "01"]);
+        let b = image(&["12", "12"]);
+        let adj = compute_adjacencies(&[a, b], &DIRECTIONS, 1);
+        // `b` continues `a` to the right, so `a` continues `b` to the left.
+        assert!(adj[0][3].contains(1) && !adj[1][3].contains(0));
+        assert!(adj[1][2].contains(0) && !adj[0][2].contains(1));
+
+        let a = image(&["00", "11"]);
+        let b = image(&["11", "22"]);
+        let adj = compute_adjacencies(&[a, b], &DIRECTIONS, 1);
+        assert!(adj[0][1].contains(1) && !adj[1][1].contains(0));
+        assert!(adj[1][0].contains(0) && !adj[0][0].contains(1));
+    }
+
+    // `count` distinct random `size x size` tiles over three colors.
+    fn random_tiles(count: usize, size: usize, seed: u64) -> Vec<Tile> {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(seed);
+        let mut tiles: Vec<Tile> = Vec::with_capacity(count);
+        while tiles.len() < count {
+            let tile: Tile = (0..size)
+                .map(|_| {
+                    (0..size)
+                        .map(|_| {
+                            let v = rng.gen_range(0..3u8) * 120;
+                            Color { r: v, g: v, b: v, a: 255 }
+                        })
+                        .collect()
+                })
+                .collect();
+            if !tiles.contains(&tile) {
+                tiles.push(tile);
+            }
+        }
+        tiles
+    }
+
+    // The pairwise comparison `compute_adjacencies` replaced: every ordered
+    // pair of tiles, pixel by pixel over their overlap.
+    fn pairwise_adjacencies(tiles: &[Tile], directions: &[(isize, isize)], stride: usize) -> Vec<Vec<TileMask>> {
+        // Whether `t2` can sit at offset `(dr, dc)` from `t1`.
+        let can_overlap = |t1: &Tile, t2: &Tile, dr: isize, dc: isize| {
+            let (rows, cols) = (t1.len() as isize, t1[0].len() as isize);
+            (0..rows).all(|r1| {
+                (0..cols).all(|c1| {
+                    let (r2, c2) = (r1 - dr, c1 - dc);
+                    r2 < 0 || r2 >= rows || c2 < 0 || c2 >= cols || t1[r1 as usize][c1 as usize] == t2[r2 as usize][c2 as usize]
+                })
+            })
+        };
+        let stride = stride as isize;
+        let mut adj = vec![vec![TileMask::empty(tiles.len()); directions.len()]; tiles.len()];
+        for i in 0..tiles.len() {
+            for j in 0..tiles.len() {
+                for (dir, &(dr, dc)) in directions.iter().enumerate() {
+                    if can_overlap(&tiles[i], &tiles[j], dr * stride, dc * stride) {
+                        adj[i][dir].insert(j);
+                    }
                 }
             }
         }
+        adj
+    }
+
+    #[test]
+    fn adjacencies_match_pairwise_reference() {
+        let tiles = random_tiles(40, 3, 11);
+        for directions in [&DIRECTIONS[..], &DIRECTIONS_8, &HEX_DIRECTIONS] {
+            for stride in 1..=3 {
+                let expected = pairwise_adjacencies(&tiles, directions, stride);
+                assert_eq!(compute_adjacencies(&tiles, directions, stride), expected, "{:?}, stride {}", directions, stride);
+            }
+        }
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_mask_grid_40_tiles() {
+        // 40 single-pixel tiles where any tile may sit next to any other.
+        let solve = |wide: bool| {
+            let tiles: Vec<Tile> = (0..40).map(|c| vec![vec![Color { r: c, g: 0, b: 0, a: 255 }]]).collect();
+            let adjacencies = vec![vec![TileMask::full(40); DIRECTIONS.len()]; 40];
+            let seen = adjacency_lists(&adjacencies);
+            let patterns = Patterns { tiles, weights: (1..=40).map(|w| w as f32).collect(), edges: vec![[true; 4]; 40] };
+            let options = EngineOptions { output_size: 256, seed: Some(1), ..EngineOptions::default() };
+            let mut e = WfcEngine::assemble(patterns, &DIRECTIONS, adjacencies, seen, (1, 1), &options).unwrap();
+            if wide {
+                // The layout before `MaskGrid`: a whole `TileMask` per cell.
+                e.matrix = MaskGrid::Masks(e.matrix.iter().map(Cow::into_owned).collect());
+            }
+            let bytes = e.matrix.bytes();
+            let start = std::time::Instant::now();
+            e.run_to_completion();
+            (start.elapsed(), bytes, e.get_tile_grid())
+        };
+        let (before, before_bytes, before_grid) = solve(true);
+        let (after, after_bytes, after_grid) = solve(false);
+
+        eprintln!("40 tiles, 256x256: TileMask per cell {:?} ({} bytes), u64 per cell {:?} ({} bytes)", before, before_bytes, after, after_bytes);
+        assert_eq!(before_grid, after_grid);
+        assert_eq!(after_bytes * 2, before_bytes);
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_adjacencies_120_tiles() {
+        let tiles = random_tiles(120, 3, 7);
+        let start = std::time::Instant::now();
+        let before = pairwise_adjacencies(&tiles, &DIRECTIONS_8, 1);
+        let before_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let after = compute_adjacencies(&tiles, &DIRECTIONS_8, 1);
+        let after_time = start.elapsed();
+
+        eprintln!("120 tiles: pairwise {:?}, compute_adjacencies {:?}", before_time, after_time);
+        assert_eq!(before, after);
+        assert!(after_time < before_time);
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_heap_selection_128() {
+        let options = EngineOptions { output_size: 128, tile_size: 2, seed: Some(1), ..EngineOptions::default() };
+        let input = image(&ROOMS);
+
+        let mut heap = engine(&input, options.clone());
+        let start = std::time::Instant::now();
+        heap.run_to_completion();
+        let heap_time = start.elapsed();
+
+        // The linear scan the heap replaced, picking by the same key.
+        let mut scan = engine(&input, options);
+        let cells = scan.matrix.len();
+        let start = std::time::Instant::now();
+        for _ in 0..cells * MAX_STEPS_PER_CELL {
+            let Some(idx) = scan.lowest_among(0..cells) else { break };
+            let tile = scan.observe(idx);
+            scan.collapse(idx, tile, 0);
+        }
+        let scan_time = start.elapsed();
+
+        eprintln!("128x128: heap {:?}, linear scan {:?}", heap_time, scan_time);
+        assert!(heap.is_complete() && scan.is_complete());
+        assert!(heap_time < scan_time);
     }
-    true
 }
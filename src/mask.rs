@@ -0,0 +1,238 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::ops::{BitAnd, BitAndAssign, BitOrAssign};
+
+/// Set of tile indices, one bit per tile. Tilesets of up to 64 patterns,
+/// the common case, keep their single `u64` word inline, so the mask is 16
+/// bytes like the `u128` it replaced, never touches the heap, and every bit
+/// op and popcount runs on the native word. Larger tilesets box as many
+/// words as they need.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TileMask {
+    words: Words,
+}
+
+// Always `One` for a single word and `Many` otherwise, so equal sets compare
+// and hash equal.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Words {
+    One(u64),
+    Many(Box<[u64]>),
+}
+
+impl TileMask {
+    pub fn empty(tile_count: usize) -> Self {
+        match tile_count.div_ceil(64) {
+            0 | 1 => TileMask { words: Words::One(0) },
+            len => TileMask { words: Words::Many(vec![0; len].into_boxed_slice()) },
+        }
+    }
+
+    fn from_words(words: Vec<u64>) -> Self {
+        let words = match words[..] {
+            [word] => Words::One(word),
+            _ => Words::Many(words.into_boxed_slice()),
+        };
+        TileMask { words }
+    }
+
+    fn words(&self) -> &[u64] {
+        match &self.words {
+            Words::One(word) => std::slice::from_ref(word),
+            Words::Many(words) => words,
+        }
+    }
+
+    fn words_mut(&mut self) -> &mut [u64] {
+        match &mut self.words {
+            Words::One(word) => std::slice::from_mut(word),
+            Words::Many(words) => words,
+        }
+    }
+
+    pub fn full(tile_count: usize) -> Self {
+        let mut mask = Self::empty(tile_count);
+        for (i, word) in mask.words_mut().iter_mut().enumerate() {
+            let bits = tile_count.saturating_sub(i * 64).min(64);
+            *word = if bits == 64 { !0 } else { (1u64 << bits) - 1 };
+        }
+        mask
+    }
+
+    pub fn single(tile_count: usize, idx: usize) -> Self {
+        let mut mask = Self::empty(tile_count);
+        mask.insert(idx);
+        mask
+    }
+
+    pub fn contains(&self, idx: usize) -> bool {
+        self.words()[idx / 64] & (1u64 << (idx % 64)) != 0
+    }
+
+    pub fn insert(&mut self, idx: usize) {
+        self.words_mut()[idx / 64] |= 1u64 << (idx % 64);
+    }
+
+    pub fn remove(&mut self, idx: usize) {
+        self.words_mut()[idx / 64] &= !(1u64 << (idx % 64));
+    }
+
+    /// Popcount word by word; each `u64` lowers to a single `i64.popcnt` on
+    /// wasm, so there is no `u128` glue in the propagation loop.
+    pub fn count_ones(&self) -> u32 {
+        self.words().iter().map(|w| w.count_ones()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words().iter().all(|&w| w == 0)
+    }
+
+    /// Whether the mask has the word count of a `tile_count` tileset and no
+    /// bits at or above `tile_count`. Masks built here always do; this is for
+    /// ones deserialized from untrusted bytes.
+    pub fn fits(&self, tile_count: usize) -> bool {
+        let full = Self::full(tile_count);
+        self.words().len() == full.words().len() && self.words().iter().zip(full.words()).all(|(w, f)| w & !f == 0)
+    }
+
+    /// The only tile in the mask, or `None` unless exactly one bit is set.
+    pub fn single_tile(&self) -> Option<usize> {
+        if self.count_ones() == 1 {
+            self.iter().next()
+        } else {
+            None
+        }
+    }
+
+    /// Bytes allocated outside the struct, zero for a single word.
+    pub fn heap_bytes(&self) -> usize {
+        match &self.words {
+            Words::One(_) => 0,
+            Words::Many(words) => std::mem::size_of_val(&**words),
+        }
+    }
+
+    /// Set tile indices in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words().iter().enumerate().flat_map(|(i, &word)| {
+            let mut bits = word;
+            std::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                Some(i * 64 + bit)
+            })
+        })
+    }
+}
+
+/// Per-cell masks of the output grid. Tilesets of up to 64 patterns, the
+/// common case, store each cell as a bare `u64`, 8 bytes per cell where a
+/// `TileMask` takes 16; larger tilesets store whole `TileMask`s. The layout
+/// is fixed at construction by the tile count. Reads hand out a `Cow`, which
+/// borrows a stored `TileMask` and builds a one-word mask on the stack for
+/// the compact layout, so neither allocates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MaskGrid {
+    Words(Vec<u64>),
+    Masks(Vec<TileMask>),
+}
+
+impl MaskGrid {
+    /// `cells` copies of `mask`, a mask for a `tile_count` tileset.
+    pub fn new(tile_count: usize, cells: usize, mask: &TileMask) -> Self {
+        match mask.words {
+            Words::One(word) if tile_count <= 64 => MaskGrid::Words(vec![word; cells]),
+            _ => MaskGrid::Masks(vec![mask.clone(); cells]),
+        }
+    }
+
+    /// The grid holding `masks`, all for a `tile_count` tileset.
+    pub fn from_masks(tile_count: usize, masks: Vec<TileMask>) -> Self {
+        if tile_count > 64 {
+            return MaskGrid::Masks(masks);
+        }
+        let words = masks.iter().map(|m| match m.words {
+            Words::One(word) => word,
+            Words::Many(_) => unreachable!("masks of up to 64 tiles have one word"),
+        });
+        MaskGrid::Words(words.collect())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            MaskGrid::Words(words) => words.len(),
+            MaskGrid::Masks(masks) => masks.len(),
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Cow<'_, TileMask> {
+        match self {
+            MaskGrid::Words(words) => Cow::Owned(TileMask { words: Words::One(words[idx]) }),
+            MaskGrid::Masks(masks) => Cow::Borrowed(&masks[idx]),
+        }
+    }
+
+    pub fn set(&mut self, idx: usize, mask: TileMask) {
+        match (self, mask.words) {
+            (MaskGrid::Words(words), Words::One(word)) => words[idx] = word,
+            (MaskGrid::Masks(masks), words) => masks[idx] = TileMask { words },
+            (MaskGrid::Words(_), Words::Many(_)) => unreachable!("masks of up to 64 tiles have one word"),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Cow<'_, TileMask>> + '_ {
+        (0..self.len()).map(|idx| self.get(idx))
+    }
+
+    /// Bytes held for the masks, inline and on the heap.
+    pub fn bytes(&self) -> usize {
+        match self {
+            MaskGrid::Words(words) => std::mem::size_of_val(&words[..]),
+            MaskGrid::Masks(masks) => masks.iter().map(|m| std::mem::size_of::<TileMask>() + m.heap_bytes()).sum(),
+        }
+    }
+}
+
+impl BitAnd for &TileMask {
+    type Output = TileMask;
+
+    fn bitand(self, other: &TileMask) -> TileMask {
+        match (&self.words, &other.words) {
+            (Words::One(a), Words::One(b)) => TileMask { words: Words::One(a & b) },
+            _ => TileMask::from_words(self.words().iter().zip(other.words()).map(|(a, b)| a & b).collect()),
+        }
+    }
+}
+
+impl BitAndAssign<&TileMask> for TileMask {
+    fn bitand_assign(&mut self, other: &TileMask) {
+        for (a, b) in self.words_mut().iter_mut().zip(other.words()) {
+            *a &= b;
+        }
+    }
+}
+
+impl BitOrAssign<&TileMask> for TileMask {
+    fn bitor_assign(&mut self, other: &TileMask) {
+        for (a, b) in self.words_mut().iter_mut().zip(other.words()) {
+            *a |= b;
+        }
+    }
+}
+
+// Serialized as the plain word sequence, whichever way it is stored.
+impl Serialize for TileMask {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.words())
+    }
+}
+
+impl<'de> Deserialize<'de> for TileMask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let words = Vec::<u64>::deserialize(deserializer)?;
+        Ok(TileMask::from_words(words))
+    }
+}
@@ -0,0 +1,125 @@
+use rand::{Error, RngCore, SeedableRng};
+
+/// Xoshiro256++, the generator behind `SmallRng` on 64-bit targets, seeded
+/// the same way, so native runs draw the same values as before. Its state
+/// is kept here rather than inside `SmallRng` so that `save_state` can store
+/// the four words themselves instead of a draw count to replay, and so wasm
+/// runs match native ones instead of falling back to Xoshiro128++. Remembers
+/// its seed and how many words it has produced. Everything is drawn through
+/// `next_u64`, which makes the count independent of which `Rng` helpers
+/// consumed it.
+pub struct EngineRng {
+    state: [u64; 4],
+    seed: u64,
+    draws: u64,
+}
+
+// Captures the 32 bytes `SeedableRng::seed_from_u64` derives from a `u64`,
+// which is how `SmallRng` expands its seed.
+struct SeedBytes([u8; 32]);
+
+impl SeedableRng for SeedBytes {
+    type Seed = [u8; 32];
+
+    fn from_seed(seed: [u8; 32]) -> Self {
+        SeedBytes(seed)
+    }
+}
+
+impl EngineRng {
+    pub fn new(seed: u64) -> Self {
+        let SeedBytes(bytes) = SeedBytes::seed_from_u64(seed);
+        let mut state = [0u64; 4];
+        for (word, chunk) in state.iter_mut().zip(bytes.chunks_exact(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().expect("8-byte chunk"));
+        }
+        if state == [0; 4] {
+            // The all-zero state never leaves zero, so swap in a fixed one.
+            state = [0x9E37_79B9_7F4A_7C15, 0xBF58_476D_1CE4_E5B9, 0x94D0_49BB_1331_11EB, 1];
+        }
+        EngineRng { state, seed, draws: 0 }
+    }
+
+    pub fn from_entropy() -> Self {
+        Self::new(rand::rngs::SmallRng::from_entropy().next_u64())
+    }
+
+    /// A generator at the position described by `state`, as returned by
+    /// `state` on the original. `None` for the all-zero state, which no
+    /// generator reaches.
+    pub fn resume(seed: u64, draws: u64, state: [u64; 4]) -> Option<Self> {
+        (state != [0; 4]).then_some(EngineRng { state, seed, draws })
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn draws(&self) -> u64 {
+        self.draws
+    }
+
+    /// The generator's internal words, enough to resume it exactly.
+    pub fn state(&self) -> [u64; 4] {
+        self.state
+    }
+
+    /// Repositions the generator at `draws` words after seeding with `seed`,
+    /// replaying only the difference when that lies ahead of the current
+    /// position. Cost is linear in the words replayed.
+    pub fn restore(&mut self, seed: u64, draws: u64) {
+        if seed != self.seed || draws < self.draws {
+            *self = Self::new(seed);
+        }
+        while self.draws < draws {
+            self.next_u64();
+        }
+    }
+}
+
+/// Uniform value in `[0, 1)` derived from its arguments alone (a SplitMix64
+/// finalizer), used where a draw must be reproducible without advancing
+/// the generator.
+pub fn hash_unit(seed: u64, a: u64, b: u64) -> f64 {
+    let mut z = seed ^ a.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ b.wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl RngCore for EngineRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.draws += 1;
+        let s = &mut self.state;
+        let result = s[0].wrapping_add(s[3]).rotate_left(23).wrapping_add(s[0]);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        fill_via_u64(self, dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        fill_via_u64(self, dest);
+        Ok(())
+    }
+}
+
+fn fill_via_u64(rng: &mut EngineRng, dest: &mut [u8]) {
+    for chunk in dest.chunks_mut(8) {
+        let bytes = rng.next_u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
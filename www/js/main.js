@@ -9,6 +9,7 @@ async function run() {
 
     const canvas = document.getElementById("wfc-canvas");
     const ctx = canvas.getContext("2d");
+    const frame = ctx.createImageData(OUTPUT_SIZE, OUTPUT_SIZE);
     const gridContainer = document.getElementById("grid-container");
     const colorPicker = document.getElementById("color-picker");
     const restartBtn = document.getElementById("restart-btn");
@@ -176,7 +177,7 @@ async function run() {
             statusText.classList.add("text-blue-600");
             progressBar.style.width = "0%";
         } catch (e) {
-            errorMsg.innerText = e;
+            errorMsg.innerText = e.message ?? e;
             errorMsg.classList.remove("hidden");
             running = false;
             statusText.innerText = "ERROR";
@@ -185,20 +186,16 @@ async function run() {
 
     function loop() {
         if (running && engine) {
-            for (let i = 0; i < 50; i++) {
-                if (!engine.step()) {
-                    running = false;
-                    statusText.innerText = "DONE";
-                    statusText.classList.remove("text-blue-600");
-                    statusText.classList.add("text-green-600");
-                    progressBar.style.width = "100%";
-                    break;
-                }
+            if (!engine.step_n(50)) {
+                running = false;
+                statusText.innerText = "DONE";
+                statusText.classList.remove("text-blue-600");
+                statusText.classList.add("text-green-600");
+                progressBar.style.width = "100%";
             }
 
-            const data = engine.get_image_data();
-            const imageData = new ImageData(new Uint8ClampedArray(data.buffer), OUTPUT_SIZE, OUTPUT_SIZE);
-            ctx.putImageData(imageData, 0, 0);
+            engine.write_image_data(frame.data);
+            ctx.putImageData(frame, 0, 0);
 
             const collapsed = engine.get_collapsed_count();
             const progress = (collapsed / (OUTPUT_SIZE * OUTPUT_SIZE)) * 100;